@@ -0,0 +1,283 @@
+#[cfg(not(feature = "nightly"))]
+use allocator_api2::alloc::{AllocError, Allocator};
+#[cfg(feature = "nightly")]
+use core::alloc::{AllocError, Allocator};
+use std::{
+    alloc::Layout,
+    ptr::{null_mut, NonNull},
+};
+
+use crate::{AllocObserver, BacktraceMode, TrackConfig, Tracker};
+
+/// Allocator wrapper for individual collections, i.e. `hashbrown::HashMap::new_in(TrackingAllocator::new(...))`,
+/// that keeps the same per-thread and per-backtrace books as `AllocTrack`.
+/// Give it its own `Tracker` with `with_tracker` to profile the footprint and churn of a single data structure in isolation.
+///
+/// Implements `allocator_api2::Allocator` with the `allocator-api2` feature, or `core::alloc::Allocator` with the `nightly` feature.
+/// Memory allocated through this wrapper is only accounted in its own tracker, even when the inner allocator is a global `AllocTrack`.
+/// Zero-sized allocations are passed through untracked.
+#[derive(Clone)]
+pub struct TrackingAllocator<A> {
+    inner: A,
+    config: TrackConfig,
+}
+
+impl<A> TrackingAllocator<A> {
+    pub const fn new(inner: A, backtrace: BacktraceMode) -> Self {
+        Self {
+            inner,
+            config: TrackConfig::new(backtrace),
+        }
+    }
+
+    /// Keep the books of this allocator in `tracker` rather than `GLOBAL_TRACKER`, to report on it separately.
+    /// Several allocators may share a tracker.
+    pub const fn with_tracker(mut self, tracker: &'static Tracker) -> Self {
+        self.config.tracker = tracker;
+        self
+    }
+
+    /// The tracker keeping the books of this allocator
+    pub fn tracker(&self) -> &'static Tracker {
+        self.config.tracker
+    }
+
+    /// Only capture a backtrace on average once every `sample_interval` allocated bytes, see `AllocTrack::with_sample_interval`.
+    pub const fn with_sample_interval(mut self, sample_interval: usize) -> Self {
+        self.config.sample_interval = sample_interval;
+        self
+    }
+
+    /// Call `observer` on every tracked allocation, free and reallocation, see `AllocObserver`
+    pub const fn with_observer(mut self, observer: &'static dyn AllocObserver) -> Self {
+        self.config.observer = Some(observer);
+        self
+    }
+
+    /// The backtrace mode currently in effect, taking `Tracker::set_backtrace_mode` into account
+    pub fn backtrace_mode(&self) -> BacktraceMode {
+        self.config.backtrace_mode()
+    }
+}
+
+fn into_raw(block: Result<NonNull<[u8]>, AllocError>) -> *mut u8 {
+    block.map_or(null_mut(), |block| block.cast::<u8>().as_ptr())
+}
+
+/// Blocks are handed out with exactly the requested size, so that they are always freed with the size they were accounted with
+fn from_raw(ptr: *mut u8, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+    let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+    Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+}
+
+unsafe impl<A: Allocator> Allocator for TrackingAllocator<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return self.inner.allocate(layout);
+        }
+        let ptr = self
+            .config
+            .alloc(layout, || into_raw(self.inner.allocate(layout)));
+        from_raw(ptr, layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return self.inner.allocate_zeroed(layout);
+        }
+        let ptr = self
+            .config
+            .alloc(layout, || into_raw(self.inner.allocate_zeroed(layout)));
+        from_raw(ptr, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            self.inner.deallocate(ptr, layout);
+            return;
+        }
+        self.config
+            .dealloc(ptr.as_ptr(), layout, || self.inner.deallocate(ptr, layout));
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let grow = || into_raw(self.inner.grow(ptr, old_layout, new_layout));
+        let new_ptr = if old_layout.size() == 0 {
+            self.config.alloc(new_layout, grow)
+        } else {
+            self.config
+                .realloc(ptr.as_ptr(), old_layout, new_layout, grow)
+        };
+        from_raw(new_ptr, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let grow = || into_raw(self.inner.grow_zeroed(ptr, old_layout, new_layout));
+        let new_ptr = if old_layout.size() == 0 {
+            self.config.alloc(new_layout, grow)
+        } else {
+            self.config
+                .realloc(ptr.as_ptr(), old_layout, new_layout, grow)
+        };
+        from_raw(new_ptr, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if new_layout.size() == 0 {
+            // becomes an untracked zero-sized block, accounted as freed only if the inner allocator succeeded
+            let mut out = Err(AllocError);
+            self.config.release(ptr.as_ptr(), old_layout, || {
+                out = self.inner.shrink(ptr, old_layout, new_layout);
+                out.is_ok()
+            });
+            return out;
+        }
+        let new_ptr = self
+            .config
+            .realloc(ptr.as_ptr(), old_layout, new_layout, || {
+                into_raw(self.inner.shrink(ptr, old_layout, new_layout))
+            });
+        from_raw(new_ptr, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "nightly"))]
+    use allocator_api2::vec::Vec;
+    use std::{
+        alloc::System,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_tracking_allocator() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = TrackingAllocator::new(System, BacktraceMode::None).with_tracker(tracker);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let block = alloc.allocate(layout).unwrap();
+        assert_eq!(block.len(), 32);
+        assert_eq!(tracker.global_stats().live_bytes, 32);
+
+        let grown = Layout::from_size_align(256, 16).unwrap();
+        let block = unsafe { alloc.grow(block.cast(), layout, grown) }.unwrap();
+        let stats = tracker.global_stats();
+        assert_eq!(stats.live_bytes, 256);
+        assert_eq!(stats.live_allocations, 1);
+        assert_eq!(tracker.live_allocations()[0].align, 16);
+
+        let empty = Layout::from_size_align(0, 1).unwrap();
+        let block = unsafe { alloc.shrink(block.cast(), grown, empty) }.unwrap();
+        let stats = tracker.global_stats();
+        assert_eq!(stats.live_allocations, 0);
+        assert_eq!(stats.total_allocated, 256);
+        assert_eq!(stats.total_freed, 256);
+        unsafe { alloc.deallocate(block.cast(), empty) };
+    }
+
+    /// `System`, except that allocating, growing and shrinking fail while the flag is set
+    struct FailingAllocator(&'static AtomicBool);
+
+    unsafe impl Allocator for FailingAllocator {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            if self.0.load(Ordering::Relaxed) {
+                return Err(AllocError);
+            }
+            System.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            System.deallocate(ptr, layout)
+        }
+
+        unsafe fn grow(
+            &self,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            if self.0.load(Ordering::Relaxed) {
+                return Err(AllocError);
+            }
+            System.grow(ptr, old_layout, new_layout)
+        }
+
+        unsafe fn shrink(
+            &self,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            if self.0.load(Ordering::Relaxed) {
+                return Err(AllocError);
+            }
+            System.shrink(ptr, old_layout, new_layout)
+        }
+    }
+
+    #[test]
+    fn test_failing_inner_allocator() {
+        static FAIL: AtomicBool = AtomicBool::new(false);
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = TrackingAllocator::new(FailingAllocator(&FAIL), BacktraceMode::None)
+            .with_tracker(tracker);
+        let mut vec = Vec::<u8, _>::with_capacity_in(64, &alloc);
+        vec.extend_from_slice(&[1; 64]);
+        assert_eq!(tracker.global_stats().live_bytes, 64);
+
+        // a failed growth leaves the block tracked, whether paused or not
+        FAIL.store(true, Ordering::Relaxed);
+        assert!(vec.try_reserve_exact(64).is_err());
+        tracker.pause();
+        assert!(vec.try_reserve_exact(64).is_err());
+        let stats = tracker.global_stats();
+        assert_eq!((stats.live_bytes, stats.live_allocations), (64, 1));
+        assert_eq!(stats.total_freed, 0);
+
+        // grown while paused, so the block is no longer tracked
+        FAIL.store(false, Ordering::Relaxed);
+        vec.try_reserve_exact(64).unwrap();
+        tracker.resume();
+        let stats = tracker.global_stats();
+        assert_eq!((stats.live_bytes, stats.total_freed), (0, 64));
+        assert_eq!(vec, [1; 64]);
+        drop(vec);
+        assert_eq!(tracker.global_stats().total_freed, 64);
+
+        // a failed shrink to zero leaves the block tracked
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let empty = Layout::from_size_align(0, 8).unwrap();
+        let block = alloc.allocate(layout).unwrap();
+        FAIL.store(true, Ordering::Relaxed);
+        assert!(unsafe { alloc.shrink(block.cast(), layout, empty) }.is_err());
+        let stats = tracker.global_stats();
+        assert_eq!((stats.live_bytes, stats.live_allocations), (32, 1));
+        assert_eq!(
+            tracker.live_allocations()[0].address,
+            block.cast::<u8>().as_ptr() as usize
+        );
+
+        FAIL.store(false, Ordering::Relaxed);
+        let block = unsafe { alloc.shrink(block.cast(), layout, empty) }.unwrap();
+        let stats = tracker.global_stats();
+        assert_eq!((stats.live_bytes, stats.total_freed), (0, 96));
+        unsafe { alloc.deallocate(block.cast(), empty) };
+    }
+}
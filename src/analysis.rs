@@ -0,0 +1,638 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read},
+};
+
+#[cfg(feature = "backtrace")]
+use crate::{backtrace_support::fmt_report_entry, BacktraceMetric};
+use crate::{
+    BacktraceMode, GlobalStats, LifetimeHistogram, PeakMetric, ReallocMetric, Record,
+    RecordingReader, SizeHistogram, ThreadMetric, ThreadReport,
+};
+
+/// A recording loaded into memory, to rebuild the reports of the recorded process at any point in time.
+/// See `Tracker::start_recording`.
+///
+/// Times are nanoseconds since tracking started in the recorded process, as in `Record`.
+/// Only events of the recording are known: allocations made before it started are ignored when freed.
+/// If the recording was made with `AllocTrack::with_sample_interval`, backtrace metrics only count sampled allocations, and are not scaled up.
+pub struct RecordingAnalysis {
+    /// Allocations, frees and reallocations, by time
+    events: Vec<Event>,
+    thread_names: HashMap<u64, String>,
+    backtraces: HashMap<u64, (String, BacktraceMode)>,
+}
+
+/// An allocation, free or step of a reallocation of a recording, taking effect at `time`.
+/// Reallocations take effect in two steps, see `Record::Realloc`, so that another thread reusing the old address
+/// before the reallocation returned is replayed in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Event {
+    Alloc {
+        time: u64,
+        ptr: u64,
+        size: u64,
+        align: u64,
+        thread_id: u64,
+        trace_hash: Option<u64>,
+    },
+    Free {
+        time: u64,
+        ptr: u64,
+        thread_id: u64,
+    },
+    /// `thread_id` handed `old_ptr` back to the allocator to reallocate it
+    ReallocStart {
+        time: u64,
+        old_ptr: u64,
+        thread_id: u64,
+    },
+    /// The reallocation started by `thread_id` returned `ptr`
+    ReallocEnd {
+        time: u64,
+        old_ptr: u64,
+        ptr: u64,
+        size: u64,
+        align: u64,
+        thread_id: u64,
+    },
+}
+
+impl Event {
+    pub(crate) fn time(&self) -> u64 {
+        match self {
+            Event::Alloc { time, .. }
+            | Event::Free { time, .. }
+            | Event::ReallocStart { time, .. }
+            | Event::ReallocEnd { time, .. } => *time,
+        }
+    }
+
+    /// Whether the event hands out an address, so that among events with the same time, addresses are released first
+    fn acquires(&self) -> bool {
+        matches!(self, Event::Alloc { .. } | Event::ReallocEnd { .. })
+    }
+}
+
+/// A live block during a replay
+struct Block {
+    size: u64,
+    owner: u64,
+    allocated_at: u64,
+    #[cfg_attr(not(feature = "backtrace"), allow(dead_code))]
+    trace_hash: Option<u64>,
+}
+
+/// Counters of a thread during a replay, as in `ThreadCounters`
+#[derive(Default)]
+struct ThreadState {
+    alloc: u64,
+    did_free: u64,
+    self_freed: u64,
+    freed_by_others: HashMap<u64, u64>,
+    realloc: ReallocMetric,
+    live: PeakMetric,
+    peak: PeakMetric,
+    lifetimes: LifetimeHistogram,
+    sizes: SizeHistogram,
+}
+
+impl ThreadState {
+    fn add(&mut self, bytes: u64, allocations: u64) {
+        self.live.bytes += bytes;
+        self.live.allocations += allocations;
+        self.peak.max(&self.live);
+    }
+
+    fn remove(&mut self, bytes: u64, allocations: u64) {
+        self.live.bytes = self.live.bytes.saturating_sub(bytes);
+        self.live.allocations = self.live.allocations.saturating_sub(allocations);
+    }
+}
+
+/// State of the recorded process at some point in time
+#[derive(Default)]
+struct Replay {
+    blocks: HashMap<u64, Block>,
+    /// Blocks being reallocated, by reallocating thread
+    moving: HashMap<u64, Block>,
+    threads: HashMap<u64, ThreadState>,
+    #[cfg(feature = "backtrace")]
+    traces: HashMap<u64, BacktraceMetric>,
+    stats: GlobalStats,
+    /// Time `stats.peak` was reached
+    peak_time: u64,
+}
+
+impl Replay {
+    fn thread(&mut self, thread_id: u64) -> &mut ThreadState {
+        self.threads.entry(thread_id).or_default()
+    }
+
+    #[cfg(feature = "backtrace")]
+    fn trace(&mut self, trace_hash: Option<u64>) -> Option<&mut BacktraceMetric> {
+        let metric = self.traces.entry(trace_hash?).or_default();
+        Some(metric)
+    }
+
+    fn live_changed(&mut self, time: u64) {
+        let live = PeakMetric {
+            bytes: self.stats.live_bytes,
+            allocations: self.stats.live_allocations,
+        };
+        if live.bytes > self.stats.peak.bytes {
+            self.peak_time = time;
+        }
+        self.stats.peak.max(&live);
+    }
+
+    fn alloc(&mut self, time: u64, ptr: u64, size: u64, thread_id: u64, trace_hash: Option<u64>) {
+        let thread = self.thread(thread_id);
+        thread.alloc += size;
+        thread.add(size, 1);
+        thread.sizes.record(size as usize, 1);
+        #[cfg(feature = "backtrace")]
+        if let Some(trace) = self.trace(trace_hash) {
+            trace.allocated += size;
+            trace.allocations += 1;
+            trace.sizes.record(size as usize, 1);
+            let in_use = PeakMetric {
+                bytes: trace.in_use(),
+                allocations: trace.live_allocations(),
+            };
+            trace.peak.max(&in_use);
+        }
+        self.stats.total_allocated += size;
+        self.stats.live_bytes += size;
+        self.stats.live_allocations += 1;
+        self.live_changed(time);
+        self.blocks.insert(
+            ptr,
+            Block {
+                size,
+                owner: thread_id,
+                allocated_at: time,
+                trace_hash,
+            },
+        );
+    }
+
+    /// Account `size` bytes of `block` as freed by `thread_id`, as in `ThreadStoreTable::free`
+    fn free_thread(&mut self, block: &Block, size: u64, thread_id: u64, lifetime: Option<u64>) {
+        self.thread(thread_id).did_free += size;
+        let owner = self.thread(block.owner);
+        if block.owner == thread_id {
+            owner.self_freed += size;
+        } else {
+            *owner.freed_by_others.entry(thread_id).or_default() += size;
+        }
+        owner.remove(size, 1);
+        if let Some(lifetime) = lifetime {
+            owner.lifetimes.record(lifetime, 1);
+        }
+    }
+
+    fn free(&mut self, time: u64, ptr: u64, thread_id: u64) {
+        let Some(block) = self.blocks.remove(&ptr) else {
+            // allocated before the recording started
+            return;
+        };
+        let lifetime = time.saturating_sub(block.allocated_at);
+        self.free_thread(&block, block.size, thread_id, Some(lifetime));
+        #[cfg(feature = "backtrace")]
+        if let Some(trace) = self.trace(block.trace_hash) {
+            trace.freed += block.size;
+            trace.frees += 1;
+            trace.lifetimes.record(lifetime, 1);
+        }
+        self.stats.total_freed += block.size;
+        self.stats.live_bytes = self.stats.live_bytes.saturating_sub(block.size);
+        self.stats.live_allocations = self.stats.live_allocations.saturating_sub(1);
+    }
+
+    fn realloc_start(&mut self, old_ptr: u64, thread_id: u64) {
+        if let Some(block) = self.blocks.remove(&old_ptr) {
+            self.moving.insert(thread_id, block);
+        }
+    }
+
+    fn realloc_end(&mut self, time: u64, old_ptr: u64, ptr: u64, size: u64, thread_id: u64) {
+        let Some(mut block) = self.moving.remove(&thread_id) else {
+            // allocated before the recording started, only its new size is known
+            self.alloc(time, ptr, size, thread_id, None);
+            return;
+        };
+        let old_size = block.size;
+        let mut realloc = ReallocMetric {
+            reallocs: 1,
+            moves: (ptr != old_ptr) as u64,
+            ..Default::default()
+        };
+        if size >= old_size {
+            realloc.grown = size - old_size;
+        } else {
+            realloc.shrunk = old_size - size;
+        }
+        let thread = self.thread(thread_id);
+        thread.realloc.add(&realloc);
+        thread.sizes.record(size as usize, 1);
+        if block.owner != thread_id {
+            self.free_thread(&block, old_size, thread_id, None);
+            let thread = self.thread(thread_id);
+            thread.alloc += size;
+            thread.add(size, 1);
+            block.owner = thread_id;
+        } else if size >= old_size {
+            thread.alloc += size - old_size;
+            thread.add(size - old_size, 0);
+        } else {
+            thread.did_free += old_size - size;
+            thread.self_freed += old_size - size;
+            thread.remove(old_size - size, 0);
+        }
+        #[cfg(feature = "backtrace")]
+        if let Some(trace) = self.trace(block.trace_hash) {
+            trace.realloc.add(&realloc);
+            trace.sizes.record(size as usize, 1);
+            trace.allocated += realloc.grown;
+            trace.freed += realloc.shrunk;
+            let in_use = PeakMetric {
+                bytes: trace.in_use(),
+                allocations: trace.live_allocations(),
+            };
+            trace.peak.max(&in_use);
+        }
+        self.stats.total_allocated += realloc.grown;
+        self.stats.total_freed += realloc.shrunk;
+        self.stats.live_bytes =
+            (self.stats.live_bytes + realloc.grown).saturating_sub(realloc.shrunk);
+        self.live_changed(time);
+        block.size = size;
+        self.blocks.insert(ptr, block);
+    }
+}
+
+impl RecordingAnalysis {
+    /// Load a recording written by `Tracker::start_recording`
+    pub fn read(reader: impl Read) -> io::Result<Self> {
+        let records = RecordingReader::new(reader)?.collect::<io::Result<Vec<_>>>()?;
+        Ok(Self::from_records(records))
+    }
+
+    /// Analyse records as read by `RecordingReader`, in any order
+    pub fn from_records(records: impl IntoIterator<Item = Record>) -> Self {
+        let mut out = Self {
+            events: vec![],
+            thread_names: HashMap::new(),
+            backtraces: HashMap::new(),
+        };
+        for record in records {
+            match record {
+                Record::Thread { thread_id, name } => {
+                    out.thread_names.insert(thread_id, name);
+                }
+                Record::Backtrace {
+                    hash,
+                    backtrace,
+                    mode,
+                } => {
+                    out.backtraces.insert(hash, (backtrace, mode));
+                }
+                Record::Alloc {
+                    time,
+                    ptr,
+                    size,
+                    align,
+                    thread_id,
+                    trace_hash,
+                } => out.events.push(Event::Alloc {
+                    time,
+                    ptr,
+                    size,
+                    align,
+                    thread_id,
+                    trace_hash,
+                }),
+                Record::Free {
+                    time,
+                    ptr,
+                    thread_id,
+                    ..
+                } => out.events.push(Event::Free {
+                    time,
+                    ptr,
+                    thread_id,
+                }),
+                Record::Realloc {
+                    time,
+                    released_at,
+                    old_ptr,
+                    ptr,
+                    size,
+                    align,
+                    thread_id,
+                    ..
+                } => out.events.extend([
+                    Event::ReallocStart {
+                        time: released_at,
+                        old_ptr,
+                        thread_id,
+                    },
+                    Event::ReallocEnd {
+                        time,
+                        old_ptr,
+                        ptr,
+                        size,
+                        align,
+                        thread_id,
+                    },
+                ]),
+            }
+        }
+        // threads write their events in batches
+        out.events
+            .sort_by_key(|event| (event.time(), event.acquires()));
+        out
+    }
+
+    /// Allocations, frees and reallocations, by time
+    pub(crate) fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Time of the first event
+    pub fn start_time(&self) -> u64 {
+        self.events.first().map(Event::time).unwrap_or(0)
+    }
+
+    /// Time of the last event
+    pub fn end_time(&self) -> u64 {
+        self.events.last().map(Event::time).unwrap_or(0)
+    }
+
+    /// Replay all events up to and including `at`
+    fn replay_until(&self, at: u64) -> Replay {
+        let mut replay = Replay::default();
+        for event in self.events.iter().take_while(|event| event.time() <= at) {
+            match *event {
+                Event::Alloc {
+                    time,
+                    ptr,
+                    size,
+                    thread_id,
+                    trace_hash,
+                    ..
+                } => replay.alloc(time, ptr, size, thread_id, trace_hash),
+                Event::Free {
+                    time,
+                    ptr,
+                    thread_id,
+                } => replay.free(time, ptr, thread_id),
+                Event::ReallocStart {
+                    old_ptr, thread_id, ..
+                } => replay.realloc_start(old_ptr, thread_id),
+                Event::ReallocEnd {
+                    time,
+                    old_ptr,
+                    ptr,
+                    size,
+                    thread_id,
+                    ..
+                } => replay.realloc_end(time, old_ptr, ptr, size, thread_id),
+            }
+        }
+        replay
+    }
+
+    fn thread_name(&self, thread_id: u64) -> String {
+        self.thread_names
+            .get(&thread_id)
+            .cloned()
+            .unwrap_or_else(|| thread_id.to_string())
+    }
+
+    /// Totals of the recorded events up to `at`, as `Tracker::global_stats` would have returned then
+    pub fn global_stats(&self, at: u64) -> GlobalStats {
+        self.replay_until(at).stats
+    }
+
+    /// Time at which the most memory allocated during the recording was live, and that memory
+    pub fn peak(&self) -> (u64, PeakMetric) {
+        let replay = self.replay_until(u64::MAX);
+        (replay.peak_time, replay.stats.peak)
+    }
+
+    /// Memory usage by thread at `at`, as `thread_report` would have reported it then.
+    /// Peaks are those reached between the start of the recording and `at`.
+    pub fn thread_report(&self, at: u64) -> ThreadReport {
+        let replay = self.replay_until(at);
+        let mut metrics: BTreeMap<String, ThreadMetric> = BTreeMap::new();
+        for (thread_id, state) in replay.threads {
+            let name = self.thread_name(thread_id);
+            let metric = metrics.entry(name.clone()).or_default();
+            metric.total_alloc += state.alloc;
+            metric.total_freed += state.did_free;
+            metric.realloc.add(&state.realloc);
+            metric.live_allocations += state.live.allocations;
+            metric.peak.max(&state.peak);
+            metric.lifetimes.add(&state.lifetimes);
+            metric.sizes.add(&state.sizes);
+            let mut total_freed = state.self_freed;
+            if state.self_freed != 0 {
+                *metric.freed_by_others.entry(name).or_default() += state.self_freed;
+            }
+            for (freed_by, freed) in state.freed_by_others {
+                total_freed += freed;
+                *metric
+                    .freed_by_others
+                    .entry(self.thread_name(freed_by))
+                    .or_default() += freed;
+            }
+            metric.total_did_free += total_freed;
+            metric.current_used += state.alloc.saturating_sub(total_freed);
+        }
+        ThreadReport(metrics)
+    }
+
+    /// Memory usage by backtrace at `at`, as `backtrace_report` would have reported it then, in the same order and layout
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace_report(&self, at: u64) -> RecordedBacktraceReport {
+        let mut out: Vec<(String, BacktraceMetric)> = self
+            .replay_until(at)
+            .traces
+            .into_iter()
+            .map(|(hash, mut metric)| {
+                let backtrace = match self.backtraces.get(&hash) {
+                    Some((backtrace, mode)) => {
+                        metric.mode = *mode;
+                        backtrace.clone()
+                    }
+                    None => format!("unknown backtrace {hash:#x}"),
+                };
+                (backtrace, metric)
+            })
+            .collect();
+        out.sort_by_key(|(_, metric)| metric.in_use());
+        RecordedBacktraceReport(out)
+    }
+
+    /// Backtraces of the allocations still live at the end of the recording, by bytes in use
+    #[cfg(feature = "backtrace")]
+    pub fn leak_report(&self) -> RecordedBacktraceReport {
+        let mut report = self.backtrace_report(u64::MAX);
+        report
+            .0
+            .retain(|(_, metric)| metric.live_allocations() != 0);
+        report
+    }
+}
+
+/// A report of backtraces of a recording and their allocation metrics, see `RecordingAnalysis::backtrace_report`
+#[cfg(feature = "backtrace")]
+pub struct RecordedBacktraceReport(pub Vec<(String, BacktraceMetric)>);
+
+#[cfg(feature = "backtrace")]
+impl std::fmt::Display for RecordedBacktraceReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (backtrace, metric) in &self.0 {
+            fmt_report_entry(f, |f| f.write_str(backtrace), metric)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "backtrace")]
+impl RecordedBacktraceReport {
+    /// Total bytes and allocations in use across all backtraces
+    pub fn in_use(&self) -> PeakMetric {
+        let mut out = PeakMetric::default();
+        for (_, metric) in &self.0 {
+            out.bytes += metric.in_use();
+            out.allocations += metric.live_allocations();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alloc(time: u64, ptr: u64, size: u64, thread_id: u64) -> Record {
+        Record::Alloc {
+            time,
+            ptr,
+            size,
+            align: 8,
+            thread_id,
+            trace_hash: Some(ptr),
+        }
+    }
+
+    #[test]
+    fn test_recording_analysis() {
+        #[cfg(feature = "backtrace")]
+        let mode = BacktraceMode::Full;
+        #[cfg(not(feature = "backtrace"))]
+        let mode = BacktraceMode::None;
+        let analysis = RecordingAnalysis::from_records([
+            Record::Thread {
+                thread_id: 1,
+                name: "main".to_string(),
+            },
+            alloc(10, 0x1000, 100, 1),
+            alloc(20, 0x2000, 50, 1),
+            Record::Free {
+                time: 40,
+                ptr: 0x1000,
+                size: 100,
+                thread_id: 2,
+            },
+            Record::Realloc {
+                time: 30,
+                released_at: 25,
+                old_ptr: 0x2000,
+                ptr: 0x3000,
+                old_size: 50,
+                size: 80,
+                align: 8,
+                thread_id: 1,
+                trace_hash: Some(0x2000),
+            },
+            Record::Thread {
+                thread_id: 2,
+                name: "worker".to_string(),
+            },
+            Record::Backtrace {
+                hash: 0x2000,
+                backtrace: "main.rs:2".to_string(),
+                mode,
+            },
+        ]);
+        assert_eq!((analysis.start_time(), analysis.end_time()), (10, 40));
+        assert_eq!(
+            analysis.peak(),
+            (
+                30,
+                PeakMetric {
+                    bytes: 180,
+                    allocations: 2
+                }
+            )
+        );
+
+        let report = analysis.thread_report(20);
+        assert_eq!(report.0["main"].current_used, 150);
+        let report = analysis.thread_report(u64::MAX);
+        let main = &report.0["main"];
+        assert_eq!(main.current_used, 80);
+        assert_eq!(main.freed_by_others["worker"], 100);
+        assert_eq!(main.realloc.grown, 30);
+        assert_eq!(report.0["worker"].total_freed, 100);
+
+        #[cfg(feature = "backtrace")]
+        {
+            let leaks = analysis.leak_report();
+            assert_eq!(leaks.0.len(), 1);
+            assert_eq!(leaks.0[0].0, "main.rs:2");
+            assert_eq!(leaks.0[0].1.in_use(), 80);
+            // by bytes in use, as `backtrace_report`
+            let report = analysis.backtrace_report(20);
+            assert_eq!(report.0.len(), 2);
+            assert_eq!(report.0[0].0, "main.rs:2");
+            assert_eq!(report.0[0].1.mode, mode);
+            assert_eq!(report.0[1].0, "unknown backtrace 0x1000");
+            assert!(report.to_string().starts_with("main.rs:2\n"));
+        }
+    }
+
+    #[test]
+    fn test_address_reused_during_realloc() {
+        // thread 2 gets the old address of a block thread 1 is moving, before thread 1's reallocation returns
+        let analysis = RecordingAnalysis::from_records([
+            alloc(10, 0x1000, 100, 1),
+            Record::Realloc {
+                time: 40,
+                released_at: 20,
+                old_ptr: 0x1000,
+                ptr: 0x2000,
+                old_size: 100,
+                size: 200,
+                align: 8,
+                thread_id: 1,
+                trace_hash: Some(0x1000),
+            },
+            alloc(30, 0x1000, 50, 2),
+        ]);
+        let stats = analysis.global_stats(u64::MAX);
+        assert_eq!((stats.live_bytes, stats.live_allocations), (250, 2));
+        let report = analysis.thread_report(u64::MAX);
+        assert_eq!(report.0["1"].current_used, 200);
+        assert_eq!(report.0["1"].realloc.moves, 1);
+        assert_eq!(report.0["2"].current_used, 50);
+
+        let replayed = analysis.replay(&std::alloc::System);
+        assert_eq!(replayed.events, 3);
+        assert_eq!(replayed.final_live_bytes, 250);
+    }
+}
@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::collections::hash_map::DefaultHasher;
 use std::fmt::{self, Write};
 use std::hash::{Hash, Hasher};
@@ -5,7 +6,9 @@ use std::hash::{Hash, Hasher};
 pub use backtrace;
 use backtrace::{Backtrace, BacktraceFmt, BytesOrWideString, PrintFmt};
 
-use crate::{BacktraceMode, Size, SizeF64};
+use crate::{
+    BacktraceMode, LifetimeHistogram, PeakMetric, ReallocMetric, Size, SizeF64, SizeHistogram,
+};
 
 #[derive(Clone)]
 pub struct HashedBacktrace {
@@ -19,6 +22,189 @@ pub(super) struct TraceInfo {
     pub freed: u64,
     pub allocations: u64,
     pub mode: BacktraceMode,
+    pub sample_interval: usize,
+    pub realloc: ReallocMetric,
+    pub failed_allocs: u64,
+    pub failed_bytes: u64,
+    pub frees: u64,
+    pub peak: PeakMetric,
+    pub lifetimes: LifetimeHistogram,
+    pub sizes: SizeHistogram,
+}
+
+impl TraceInfo {
+    pub fn new(backtrace: HashedBacktrace, mode: BacktraceMode, sample_interval: usize) -> Self {
+        Self {
+            backtrace,
+            allocated: 0,
+            freed: 0,
+            allocations: 0,
+            mode,
+            sample_interval,
+            realloc: ReallocMetric::default(),
+            failed_allocs: 0,
+            failed_bytes: 0,
+            frees: 0,
+            peak: PeakMetric::default(),
+            lifetimes: LifetimeHistogram::default(),
+            sizes: SizeHistogram::default(),
+        }
+    }
+
+    pub fn alloc(&mut self, size: usize, sample_weight: f64) {
+        self.allocated += weighted(size, sample_weight);
+        self.allocations += weighted(1, sample_weight);
+        self.sizes.record(size, weighted(1, sample_weight));
+        self.update_peak();
+    }
+
+    pub fn free(&mut self, size: usize, sample_weight: f64, lifetime_nanos: u64) {
+        self.freed += weighted(size, sample_weight);
+        self.frees += weighted(1, sample_weight);
+        self.lifetimes
+            .record(lifetime_nanos, weighted(1, sample_weight));
+    }
+
+    pub fn realloc(&mut self, old_size: usize, new_size: usize, moved: bool, sample_weight: f64) {
+        self.realloc.reallocs += weighted(1, sample_weight);
+        self.sizes.record(new_size, weighted(1, sample_weight));
+        if moved {
+            self.realloc.moves += weighted(1, sample_weight);
+        }
+        if new_size >= old_size {
+            self.allocated += weighted(new_size - old_size, sample_weight);
+            self.realloc.grown += weighted(new_size - old_size, sample_weight);
+            self.update_peak();
+        } else {
+            self.freed += weighted(old_size - new_size, sample_weight);
+            self.realloc.shrunk += weighted(old_size - new_size, sample_weight);
+        }
+    }
+
+    fn update_peak(&mut self) {
+        self.peak.max(&self.current());
+    }
+
+    fn current(&self) -> PeakMetric {
+        PeakMetric {
+            bytes: self.allocated.saturating_sub(self.freed),
+            allocations: self.allocations.saturating_sub(self.frees),
+        }
+    }
+
+    /// Estimated bytes in use, as in `BacktraceMetric::in_use`
+    pub fn in_use(&self) -> u64 {
+        self.current().bytes
+    }
+
+    pub fn reset_peak(&mut self) {
+        self.peak = self.current();
+    }
+
+    pub fn metric(&self) -> BacktraceMetric {
+        BacktraceMetric {
+            allocated: self.allocated,
+            freed: self.freed,
+            mode: self.mode,
+            allocations: self.allocations,
+            sample_interval: self.sample_interval,
+            realloc: self.realloc,
+            failed_allocs: self.failed_allocs,
+            failed_bytes: self.failed_bytes,
+            frees: self.frees,
+            peak: self.peak,
+            lifetimes: self.lifetimes,
+            sizes: self.sizes,
+        }
+    }
+}
+
+/// Number of sampling countdowns kept per thread, see `BYTES_UNTIL_SAMPLE`
+const SAMPLE_COUNTDOWNS: usize = 8;
+
+/// Bytes left to allocate on a thread before the next sample of one tracker
+#[derive(Clone, Copy)]
+struct SampleCountdown {
+    /// Address of the tracker, 0 if unused
+    tracker: usize,
+    sample_interval: usize,
+    remaining: i64,
+}
+
+thread_local! {
+    /// Sampling countdowns of this thread by tracker and sample interval, so that allocators with different intervals
+    /// don't skew each other's sampling. Direct-mapped: a countdown displaced by another tracker is drawn anew,
+    /// which keeps estimates unbiased as the exponential distribution is memoryless.
+    static BYTES_UNTIL_SAMPLE: Cell<[SampleCountdown; SAMPLE_COUNTDOWNS]> = const {
+        Cell::new(
+            [SampleCountdown {
+                tracker: 0,
+                sample_interval: 0,
+                remaining: 0,
+            }; SAMPLE_COUNTDOWNS],
+        )
+    };
+    /// xorshift state for drawing sample intervals
+    static SAMPLE_RNG: Cell<u64> = const { Cell::new(0) };
+}
+
+fn next_random() -> u64 {
+    SAMPLE_RNG.with(|rng| {
+        let mut x = rng.get();
+        if x == 0 {
+            // seed from the address of the thread local, which differs between threads
+            x = (rng as *const Cell<u64> as u64) ^ 0x9E37_79B9_7F4A_7C15;
+        }
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        x
+    })
+}
+
+/// Draws the number of bytes until the next sample from an exponential distribution with mean `sample_interval`,
+/// making sampling a Poisson process over allocated bytes.
+fn next_sample_distance(sample_interval: usize) -> i64 {
+    // 53 random bits mapped to (0, 1]
+    let uniform = ((next_random() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+    (-uniform.ln() * sample_interval as f64) as i64 + 1
+}
+
+/// Decides whether an allocation of `size` bytes by the tracker at address `tracker` is sampled.
+/// Returns the weight of the sample, i.e. the inverse of the probability of it being sampled.
+pub(super) fn sample_allocation(
+    tracker: usize,
+    size: usize,
+    sample_interval: usize,
+) -> Option<f64> {
+    BYTES_UNTIL_SAMPLE.with(|countdowns| {
+        let mut all = countdowns.get();
+        let countdown = &mut all[((tracker / 8) ^ sample_interval) % SAMPLE_COUNTDOWNS];
+        if countdown.tracker != tracker || countdown.sample_interval != sample_interval {
+            *countdown = SampleCountdown {
+                tracker,
+                sample_interval,
+                remaining: next_sample_distance(sample_interval),
+            };
+        }
+        countdown.remaining -= size as i64;
+        let sampled = countdown.remaining <= 0;
+        if sampled {
+            countdown.remaining = next_sample_distance(sample_interval);
+        }
+        countdowns.set(all);
+        if !sampled {
+            return None;
+        }
+        let probability = 1.0 - (-(size as f64) / sample_interval as f64).exp();
+        Some(1.0 / probability)
+    })
+}
+
+/// Scales `value` by a sample weight
+pub(super) fn weighted(value: usize, sample_weight: f64) -> u64 {
+    (value as f64 * sample_weight).round() as u64
 }
 
 struct HashedBacktraceShort<'a>(&'a HashedBacktrace);
@@ -88,6 +274,8 @@ impl HashedBacktrace {
                     let name = name.strip_prefix('<').unwrap_or(&name);
                     if name.starts_with("alloc_track::")
                         || name == "__rg_alloc"
+                        || name == "__rg_realloc"
+                        || name.starts_with("__rustc::")
                         || name.starts_with("alloc::")
                         || name.starts_with("std::panicking::")
                         || name == "__rust_try"
@@ -136,6 +324,23 @@ pub struct BacktraceMetric {
     pub allocations: u64,
     /// `mode` as copied from `AllocTrack`
     pub mode: BacktraceMode,
+    /// Average number of allocated bytes between samples, as copied from `AllocTrack`.
+    /// If nonzero, the other fields are estimates extrapolated from sampled allocations.
+    pub sample_interval: usize,
+    /// Reallocations of allocations made here. Growing and shrinking is included in `allocated` and `freed`.
+    pub realloc: ReallocMetric,
+    /// Number of allocations or reallocations that failed here. These are not sampled.
+    pub failed_allocs: u64,
+    /// Number of bytes requested by failed allocations or reallocations
+    pub failed_bytes: u64,
+    /// Number of allocations made here that have since been freed
+    pub frees: u64,
+    /// Peak of `in_use` and `live_allocations`
+    pub peak: PeakMetric,
+    /// How long allocations made here lived before being freed
+    pub lifetimes: LifetimeHistogram,
+    /// Sizes of allocations and reallocations made here
+    pub sizes: SizeHistogram,
 }
 
 impl BacktraceMetric {
@@ -144,6 +349,11 @@ impl BacktraceMetric {
         self.allocated.saturating_sub(self.freed)
     }
 
+    /// Number of allocations made here that are not freed
+    pub fn live_allocations(&self) -> u64 {
+        self.allocations.saturating_sub(self.frees)
+    }
+
     /// Average number of bytes per allocation
     pub fn avg_allocation(&self) -> f64 {
         if self.allocations == 0 {
@@ -161,6 +371,26 @@ impl fmt::Display for BacktraceMetric {
         writeln!(f, "avg_allocation: {}", SizeF64(self.avg_allocation()))?;
         writeln!(f, "freed: {}", Size(self.freed))?;
         writeln!(f, "total_used: {}", Size(self.in_use()))?;
+        writeln!(f, "live_allocations: {}", self.live_allocations())?;
+        writeln!(f, "peak_used: {}", self.peak)?;
+        write!(f, "{}", self.realloc)?;
+        write!(f, "{}", self.sizes)?;
+        write!(f, "{}", self.lifetimes)?;
+        if self.failed_allocs != 0 {
+            writeln!(
+                f,
+                "failed_allocs: {} ({})",
+                self.failed_allocs,
+                Size(self.failed_bytes)
+            )?;
+        }
+        if self.sample_interval != 0 {
+            writeln!(
+                f,
+                "sampled: every {} (estimated)",
+                Size(self.sample_interval as u64)
+            )?;
+        }
         Ok(())
     }
 }
@@ -183,57 +413,114 @@ impl BacktraceMetric {
 /// A report of all (post-filter) backtraces and their associated allocations metrics.
 pub struct BacktraceReport(pub Vec<(HashedBacktrace, BacktraceMetric)>);
 
+/// Writes `backtrace` as configured by `mode`
+pub(crate) fn fmt_backtrace(
+    f: &mut fmt::Formatter<'_>,
+    backtrace: &HashedBacktrace,
+    mode: BacktraceMode,
+) -> fmt::Result {
+    match mode {
+        BacktraceMode::None => unreachable!(),
+        BacktraceMode::Short => write!(f, "{}", HashedBacktraceShort(backtrace)),
+        BacktraceMode::Full => write!(f, "{:?}", backtrace.inner()),
+    }
+}
+
+/// `backtrace` as configured by `mode`
+pub(crate) fn backtrace_string(backtrace: &HashedBacktrace, mode: BacktraceMode) -> String {
+    match mode {
+        BacktraceMode::None => unreachable!(),
+        BacktraceMode::Short => HashedBacktraceShort(backtrace).to_string(),
+        BacktraceMode::Full => format!("{:?}", backtrace.inner()),
+    }
+}
+
+/// `backtrace` as configured by `mode`, as a quoted and escaped CSV field
+pub(crate) fn csv_backtrace(backtrace: &HashedBacktrace, mode: BacktraceMode) -> String {
+    let backtrace = backtrace_string(backtrace, mode);
+    format!(
+        "\"{}\"",
+        backtrace.replace('\\', "\\\\").replace('\n', "\\n")
+    )
+}
+
 impl BacktraceReport {
     pub fn csv(&self) -> String {
         let mut out = String::new();
-        write!(
+        writeln!(
             &mut out,
-            "allocated,allocations,avg_allocation,freed,total_used,backtrace\n"
+            "allocated,allocations,avg_allocation,freed,total_used,backtrace"
         )
         .unwrap();
         for (backtrace, metric) in &self.0 {
-            match metric.mode {
-                BacktraceMode::None => unreachable!(),
-                BacktraceMode::Short => {
-                    metric.csv_write(&mut out).unwrap();
-                    writeln!(
-                        &mut out,
-                        ",\"{}\"",
-                        HashedBacktraceShort(backtrace)
-                            .to_string()
-                            .replace("\\", "\\\\")
-                            .replace("\n", "\\n")
-                    )
-                    .unwrap();
-                }
-                BacktraceMode::Full => {
-                    metric.csv_write(&mut out).unwrap();
-                    writeln!(
-                        &mut out,
-                        ",\"{}\"",
-                        format!("{:?}", backtrace.inner())
-                            .replace("\\", "\\\\")
-                            .replace("\n", "\\n")
-                    )
-                    .unwrap();
-                }
-            }
+            metric.csv_write(&mut out).unwrap();
+            writeln!(&mut out, ",{}", csv_backtrace(backtrace, metric.mode)).unwrap();
         }
         out
     }
 }
 
+/// Writes a backtrace, by `fmt_backtrace`, and its metric as one entry of a backtrace report
+pub(crate) fn fmt_report_entry(
+    f: &mut fmt::Formatter<'_>,
+    fmt_backtrace: impl FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+    metric: &BacktraceMetric,
+) -> fmt::Result {
+    fmt_backtrace(f)?;
+    writeln!(f, "\n{metric}\n\n")
+}
+
 impl fmt::Display for BacktraceReport {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for (backtrace, metric) in &self.0 {
-            match metric.mode {
-                BacktraceMode::None => unreachable!(),
-                BacktraceMode::Short => {
-                    writeln!(f, "{}\n{metric}\n\n", HashedBacktraceShort(backtrace))?
+            fmt_report_entry(f, |f| fmt_backtrace(f, backtrace, metric.mode), metric)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_sampling_unbiased() {
+        let sample_interval = 64 * 1024;
+        let mut estimated = 0u64;
+        let mut actual = 0u64;
+        for i in 0..1_000_000usize {
+            let size = 16 + (i % 7) * 100;
+            actual += size as u64;
+            if let Some(weight) = sample_allocation(8, size, sample_interval) {
+                estimated += weighted(size, weight);
+            }
+        }
+        let error = (estimated as f64 - actual as f64).abs() / actual as f64;
+        assert!(error < 0.1, "estimated {estimated} for {actual} bytes");
+    }
+
+    #[test]
+    pub fn test_sampling_per_tracker() {
+        // interleaved allocations of trackers with very different intervals, including two sharing a countdown slot
+        let trackers = [
+            (16, 64 * 1024),
+            (32, 512),
+            (16 + 8 * SAMPLE_COUNTDOWNS, 4096),
+        ];
+        let mut estimated = [0u64; 3];
+        let mut actual = 0u64;
+        for i in 0..1_000_000usize {
+            let size = 16 + (i % 7) * 100;
+            actual += size as u64;
+            for (j, (tracker, sample_interval)) in trackers.into_iter().enumerate() {
+                if let Some(weight) = sample_allocation(tracker, size, sample_interval) {
+                    estimated[j] += weighted(size, weight);
                 }
-                BacktraceMode::Full => writeln!(f, "{:?}\n{metric}\n\n", backtrace.inner())?,
             }
         }
-        Ok(())
+        for estimated in estimated {
+            let error = (estimated as f64 - actual as f64).abs() / actual as f64;
+            assert!(error < 0.1, "estimated {estimated} for {actual} bytes");
+        }
     }
 }
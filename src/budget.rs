@@ -0,0 +1,311 @@
+use std::{
+    alloc::Layout,
+    cell::OnceCell,
+    sync::{atomic::Ordering, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{
+    current_thread_name, enter_alloc,
+    tags::{NO_TAG, TAG_NAMES, TAG_PATH_SEPARATOR},
+    thread_store::current_thread,
+    tracker::TrackerMaps,
+    Tracker, Untracked, GLOBAL_TRACKER,
+};
+
+/// What a `Budget` limits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BudgetScope {
+    /// All live allocations of the tracker
+    Global,
+    /// Live allocations owned by each thread of this name, as named in `thread_report`.
+    /// Every thread of that name is limited individually. A thread's name is looked up once, on its first budget check,
+    /// so a thread renamed afterwards keeps being limited by the budget of its former name.
+    Thread(String),
+    /// Live allocations made with this tag path active, i.e. `"cache/lru"`, including nested tags
+    Tag(&'static str),
+}
+
+/// A limit on live bytes, see `Tracker::set_budget`
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    /// Maximum number of live bytes
+    pub limit: u64,
+    /// Whether allocations that would exceed the limit fail, rather than only being reported to `on_exceeded`
+    pub deny: bool,
+    /// Called for every allocation that would exceed the limit
+    pub on_exceeded: Option<fn(&BudgetExceeded)>,
+}
+
+impl Budget {
+    /// A budget of `limit` live bytes, that neither denies allocations nor calls back until configured to
+    pub const fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            deny: false,
+            on_exceeded: None,
+        }
+    }
+
+    /// Make allocations that would exceed the limit fail, so that `handle_alloc_error` fires instead of the process running out of memory.
+    /// Denied allocations are accounted as failed allocations, and passed to the OOM hook.
+    pub const fn deny(mut self) -> Self {
+        self.deny = true;
+        self
+    }
+
+    /// Call `callback` for every allocation that would exceed the limit.
+    /// The callback runs inside the allocator: its own allocations are not tracked, it must not panic, and it must not change budgets.
+    pub const fn on_exceeded(mut self, callback: fn(&BudgetExceeded)) -> Self {
+        self.on_exceeded = Some(callback);
+        self
+    }
+}
+
+/// An allocation that would exceed a budget, passed to `Budget::on_exceeded`
+#[derive(Debug)]
+pub struct BudgetExceeded<'a> {
+    pub scope: &'a BudgetScope,
+    pub limit: u64,
+    /// Live bytes in the scope before the allocation
+    pub in_use: u64,
+    /// Requested layout. For reallocations, this is the layout of the requested new size.
+    pub layout: Layout,
+    /// Bytes the allocation would add to the scope. For reallocations, this is less than `layout.size()`,
+    /// except for thread budgets when the block moves from the thread that allocated it to the reallocating thread.
+    pub requested: u64,
+    /// alloc-track id of the allocating thread
+    pub thread_id: usize,
+    /// Whether the allocation is denied
+    pub denied: bool,
+}
+
+struct BudgetEntry {
+    scope: BudgetScope,
+    /// Tag id of a `BudgetScope::Tag`
+    tag: usize,
+    budget: Budget,
+}
+
+/// The budgets of a tracker
+pub(crate) struct Budgets {
+    entries: Vec<BudgetEntry>,
+}
+
+impl Budgets {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+thread_local! {
+    /// Name of the current thread as in `thread_report`, looked up on the first check of a thread budget and never again,
+    /// as reading it on every allocation would cost a system call
+    static THREAD_NAME: Untracked<OnceCell<String>> = const { Untracked::new(OnceCell::new()) };
+}
+
+/// Whether the current thread is named `name`. Must be called with `IN_ALLOC` set.
+fn is_current_thread(name: &str) -> bool {
+    THREAD_NAME
+        .try_with(|thread_name| {
+            thread_name.get_or_init(|| {
+                current_thread_name().unwrap_or_else(|| current_thread().uid.to_string())
+            }) == name
+        })
+        .unwrap_or(false)
+}
+
+/// Budgets of the global tracker, see `Tracker::set_budget`
+pub fn set_budget(scope: BudgetScope, budget: Budget) {
+    GLOBAL_TRACKER.set_budget(scope, budget);
+}
+
+/// Remove a budget of the global tracker, see `Tracker::remove_budget`
+pub fn remove_budget(scope: &BudgetScope) {
+    GLOBAL_TRACKER.remove_budget(scope);
+}
+
+impl Tracker {
+    fn write_budgets(&self) -> RwLockWriteGuard<'_, Budgets> {
+        self.budgets
+            .write()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    fn read_budgets(&self) -> RwLockReadGuard<'_, Budgets> {
+        self.budgets
+            .read()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    /// Limit the live bytes of `scope`, replacing any budget previously set for it.
+    /// Budgets are checked before every allocation and growing reallocation, against the same counters as `global_stats`,
+    /// `thread_report` and `tag_report`. Allocations made before a budget was set count towards it.
+    pub fn set_budget(&self, scope: BudgetScope, budget: Budget) {
+        enter_alloc(|| {
+            let tag = match scope {
+                BudgetScope::Tag(path) => path
+                    .split(TAG_PATH_SEPARATOR)
+                    .fold(NO_TAG, |parent, name| TAG_NAMES.id(parent, "", name)),
+                _ => NO_TAG,
+            };
+            let mut budgets = self.write_budgets();
+            budgets.entries.retain(|entry| entry.scope != scope);
+            budgets.entries.push(BudgetEntry { scope, tag, budget });
+            self.has_budgets.store(true, Ordering::Release);
+        });
+    }
+
+    /// Remove the budget set for `scope`, if any
+    pub fn remove_budget(&self, scope: &BudgetScope) {
+        enter_alloc(|| {
+            let mut budgets = self.write_budgets();
+            budgets.entries.retain(|entry| entry.scope != *scope);
+            self.has_budgets
+                .store(!budgets.entries.is_empty(), Ordering::Release);
+        });
+    }
+
+    /// Check an allocation growing live memory by `requested` bytes in `tag`, and the live memory of the current thread by `thread_requested` bytes,
+    /// against all budgets, calling back for any it exceeds. Scopes that do not grow are not checked.
+    /// Returns false if the allocation is denied. Must be called with `IN_ALLOC` set.
+    pub(crate) fn check_budgets(
+        &'static self,
+        maps: &TrackerMaps,
+        layout: Layout,
+        requested: usize,
+        thread_requested: usize,
+        tag: usize,
+    ) -> bool {
+        if !self.has_budgets.load(Ordering::Acquire) {
+            return true;
+        }
+        let budgets = self.read_budgets();
+        let mut allowed = true;
+        for entry in &budgets.entries {
+            let (in_use, requested) = match &entry.scope {
+                BudgetScope::Global => (self.live.load().bytes, requested),
+                BudgetScope::Thread(name) => {
+                    if !is_current_thread(name) {
+                        continue;
+                    }
+                    (
+                        self.threads.current_slot().1.live.load().bytes,
+                        thread_requested,
+                    )
+                }
+                BudgetScope::Tag(_) => {
+                    if !TAG_NAMES.is_within(tag, entry.tag) {
+                        continue;
+                    }
+                    (maps.tags.live_bytes(entry.tag), requested)
+                }
+            };
+            if requested == 0 {
+                continue;
+            }
+            let in_use = in_use as u64;
+            if in_use + requested as u64 <= entry.budget.limit {
+                continue;
+            }
+            if let Some(callback) = entry.budget.on_exceeded {
+                callback(&BudgetExceeded {
+                    scope: &entry.scope,
+                    limit: entry.budget.limit,
+                    in_use,
+                    layout,
+                    requested: requested as u64,
+                    thread_id: current_thread().uid,
+                    denied: entry.budget.deny,
+                });
+            }
+            allowed &= !entry.budget.deny;
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, System},
+        sync::atomic::AtomicUsize,
+    };
+
+    use super::*;
+    use crate::{with_tag, AllocTrack, BacktraceMode};
+
+    static EXCEEDED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_exceeded(exceeded: &BudgetExceeded) {
+        assert_eq!(exceeded.scope, &BudgetScope::Tag("test_budget"));
+        EXCEEDED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_tag_budget() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker);
+        tracker.set_budget(
+            BudgetScope::Tag("test_budget"),
+            Budget::new(100).deny().on_exceeded(count_exceeded),
+        );
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let (first, second) = with_tag("test_budget", || unsafe {
+            (
+                alloc.alloc(layout),
+                with_tag("inner", || alloc.alloc(layout)),
+            )
+        });
+        assert!(!first.is_null());
+        assert!(second.is_null());
+        assert_eq!(EXCEEDED.load(Ordering::Relaxed), 1);
+
+        // outside of the tag, and growing within the budget
+        let untagged = unsafe { alloc.alloc(layout) };
+        assert!(!untagged.is_null());
+        let first = unsafe { alloc.realloc(first, layout, 96) };
+        assert!(!first.is_null());
+        assert!(
+            unsafe { alloc.realloc(first, Layout::from_size_align(96, 8).unwrap(), 128) }.is_null()
+        );
+        assert_eq!(EXCEEDED.load(Ordering::Relaxed), 2);
+
+        tracker.remove_budget(&BudgetScope::Tag("test_budget"));
+        let second = with_tag("test_budget", || unsafe { alloc.alloc(layout) });
+        assert!(!second.is_null());
+        unsafe {
+            alloc.dealloc(first, Layout::from_size_align(96, 8).unwrap());
+            alloc.dealloc(second, layout);
+            alloc.dealloc(untagged, layout);
+        }
+    }
+
+    #[test]
+    fn test_thread_budget_realloc_from_other_thread() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc: &'static AllocTrack<System> = Box::leak(Box::new(
+            AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker),
+        ));
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) } as usize;
+        std::thread::spawn(move || {
+            let name = enter_alloc(|| {
+                current_thread_name().unwrap_or_else(|| current_thread().uid.to_string())
+            });
+            tracker.set_budget(BudgetScope::Thread(name), Budget::new(48).deny());
+            let ptr = ptr as *mut u8;
+            // the whole block would move to this thread, although shrinking
+            assert!(unsafe { alloc.realloc(ptr, layout, 56) }.is_null());
+            let ptr = unsafe { alloc.realloc(ptr, layout, 32) };
+            assert!(!ptr.is_null());
+            // now owned by this thread, growing by 8 bytes
+            let ptr = unsafe { alloc.realloc(ptr, Layout::from_size_align(32, 8).unwrap(), 40) };
+            assert!(!ptr.is_null());
+            unsafe { alloc.dealloc(ptr, Layout::from_size_align(40, 8).unwrap()) };
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+use crate::Size;
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Number of buckets in a `LifetimeHistogram`
+pub const LIFETIME_BUCKETS: usize = 12;
+
+const MICROSECOND: u64 = 1_000;
+const MILLISECOND: u64 = 1_000 * MICROSECOND;
+const SECOND: u64 = 1_000 * MILLISECOND;
+const MINUTE: u64 = 60 * SECOND;
+
+/// Exclusive upper bounds of all but the last bucket, in nanoseconds
+const LIFETIME_BOUNDS: [u64; LIFETIME_BUCKETS - 1] = [
+    MICROSECOND,
+    10 * MICROSECOND,
+    100 * MICROSECOND,
+    MILLISECOND,
+    10 * MILLISECOND,
+    100 * MILLISECOND,
+    SECOND,
+    10 * SECOND,
+    MINUTE,
+    10 * MINUTE,
+    60 * MINUTE,
+];
+
+const LIFETIME_LABELS: [&str; LIFETIME_BUCKETS] = [
+    "<1us", "<10us", "<100us", "<1ms", "<10ms", "<100ms", "<1s", "<10s", "<1m", "<10m", "<1h",
+    ">=1h",
+];
+
+/// Number of freed allocations by how long they were live, in log-scale buckets from under a microsecond to over an hour.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LifetimeHistogram {
+    /// Number of allocations per bucket, see `bucket_label`
+    pub buckets: [u64; LIFETIME_BUCKETS],
+}
+
+impl LifetimeHistogram {
+    /// Index of the bucket counting allocations that lived for `lifetime`
+    pub fn bucket(lifetime: Duration) -> usize {
+        bucket_of(lifetime.as_nanos().min(u64::MAX as u128) as u64)
+    }
+
+    /// Human readable range of the bucket at `index`, i.e. `"<10ms"`
+    pub fn bucket_label(index: usize) -> &'static str {
+        LIFETIME_LABELS[index]
+    }
+
+    /// Exclusive upper bound of the bucket at `index`, or `None` for the last bucket
+    pub fn bucket_bound(index: usize) -> Option<Duration> {
+        LIFETIME_BOUNDS.get(index).map(|x| Duration::from_nanos(*x))
+    }
+
+    pub(crate) fn record(&mut self, lifetime_nanos: u64, count: u64) {
+        self.buckets[bucket_of(lifetime_nanos)] += count;
+    }
+
+    /// Add the counts of `other` onto this histogram
+    pub fn add(&mut self, other: &LifetimeHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+    }
+
+    /// Total number of allocations counted
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+fn bucket_of(lifetime_nanos: u64) -> usize {
+    LIFETIME_BOUNDS.partition_point(|bound| *bound <= lifetime_nanos)
+}
+
+impl fmt::Display for LifetimeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count() == 0 {
+            return Ok(());
+        }
+        write!(f, "lifetimes:")?;
+        let mut first = true;
+        for (index, count) in self.buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let separator = if first { " " } else { ", " };
+            write!(f, "{separator}{}: {count}", LIFETIME_LABELS[index])?;
+            first = false;
+        }
+        writeln!(f)
+    }
+}
+
+/// Number of buckets in a `SizeHistogram`
+pub const SIZE_BUCKETS: usize = 41;
+
+/// Number of allocations and reallocations by requested size, in power-of-two buckets.
+/// Bucket `i` counts sizes in `(2^(i-1), 2^i]`, except for the last bucket which counts all larger sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// Number of allocations per bucket, see `bucket_label`
+    pub buckets: [u64; SIZE_BUCKETS],
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; SIZE_BUCKETS],
+        }
+    }
+}
+
+impl SizeHistogram {
+    /// Index of the bucket counting allocations of `size` bytes
+    pub fn bucket(size: usize) -> usize {
+        size_bucket_of(size)
+    }
+
+    /// Human readable range of the bucket at `index`, i.e. `"<=16 B"`
+    pub fn bucket_label(index: usize) -> String {
+        match Self::bucket_bound(index) {
+            Some(bound) => format!("<={}", Size(bound as u64)),
+            None => format!(">{}", Size(1 << (SIZE_BUCKETS - 2))),
+        }
+    }
+
+    /// Inclusive upper bound of the bucket at `index`, or `None` for the last bucket
+    pub fn bucket_bound(index: usize) -> Option<usize> {
+        (index < SIZE_BUCKETS - 1).then(|| 1 << index)
+    }
+
+    #[cfg_attr(not(feature = "backtrace"), allow(dead_code))]
+    pub(crate) fn record(&mut self, size: usize, count: u64) {
+        self.buckets[size_bucket_of(size)] += count;
+    }
+
+    /// Add the counts of `other` onto this histogram
+    pub fn add(&mut self, other: &SizeHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+    }
+
+    /// Total number of allocations counted
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+fn size_bucket_of(size: usize) -> usize {
+    if size <= 1 {
+        return 0;
+    }
+    ((usize::BITS - (size - 1).leading_zeros()) as usize).min(SIZE_BUCKETS - 1)
+}
+
+impl fmt::Display for SizeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count() == 0 {
+            return Ok(());
+        }
+        write!(f, "sizes:")?;
+        let mut first = true;
+        for (index, count) in self.buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let separator = if first { " " } else { ", " };
+            write!(f, "{separator}{}: {count}", Self::bucket_label(index))?;
+            first = false;
+        }
+        writeln!(f)
+    }
+}
+
+/// Atomic histogram counters with `N` buckets, for counters shared between threads
+pub(crate) struct AtomicHistogram<const N: usize> {
+    buckets: [AtomicUsize; N],
+}
+
+impl<const N: usize> AtomicHistogram<N> {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicUsize::new(0) }; N],
+        }
+    }
+
+    fn load_buckets(&self) -> [u64; N] {
+        self.buckets
+            .each_ref()
+            .map(|x| x.load(Ordering::Relaxed) as u64)
+    }
+
+    fn take_buckets(&self) -> [u64; N] {
+        self.buckets
+            .each_ref()
+            .map(|x| x.swap(0, Ordering::Relaxed) as u64)
+    }
+
+    fn merge_buckets(&self, buckets: &[u64; N]) {
+        for (bucket, count) in self.buckets.iter().zip(buckets) {
+            bucket.fetch_add(*count as usize, Ordering::Relaxed);
+        }
+    }
+}
+
+impl AtomicHistogram<LIFETIME_BUCKETS> {
+    pub fn record_lifetime(&self, lifetime_nanos: u64) {
+        self.buckets[bucket_of(lifetime_nanos)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn load_lifetimes(&self) -> LifetimeHistogram {
+        LifetimeHistogram {
+            buckets: self.load_buckets(),
+        }
+    }
+
+    pub fn take_lifetimes(&self) -> LifetimeHistogram {
+        LifetimeHistogram {
+            buckets: self.take_buckets(),
+        }
+    }
+
+    pub fn merge_lifetimes(&self, other: &LifetimeHistogram) {
+        self.merge_buckets(&other.buckets);
+    }
+}
+
+impl AtomicHistogram<SIZE_BUCKETS> {
+    pub fn record_size(&self, size: usize) {
+        self.buckets[size_bucket_of(size)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn load_sizes(&self) -> SizeHistogram {
+        SizeHistogram {
+            buckets: self.load_buckets(),
+        }
+    }
+
+    pub fn take_sizes(&self) -> SizeHistogram {
+        SizeHistogram {
+            buckets: self.take_buckets(),
+        }
+    }
+
+    pub fn merge_sizes(&self, other: &SizeHistogram) {
+        self.merge_buckets(&other.buckets);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifetime_buckets() {
+        assert_eq!(LifetimeHistogram::bucket(Duration::ZERO), 0);
+        assert_eq!(LifetimeHistogram::bucket(Duration::from_nanos(999)), 0);
+        assert_eq!(LifetimeHistogram::bucket(Duration::from_micros(1)), 1);
+        assert_eq!(LifetimeHistogram::bucket(Duration::from_millis(5)), 4);
+        assert_eq!(LifetimeHistogram::bucket(Duration::from_secs(59)), 8);
+        assert_eq!(LifetimeHistogram::bucket(Duration::from_secs(3600)), 11);
+        assert_eq!(LifetimeHistogram::bucket(Duration::MAX), 11);
+        for index in 0..LIFETIME_BUCKETS - 1 {
+            let bound = LifetimeHistogram::bucket_bound(index).unwrap();
+            assert_eq!(
+                LifetimeHistogram::bucket(bound - Duration::from_nanos(1)),
+                index
+            );
+            assert_eq!(LifetimeHistogram::bucket(bound), index + 1);
+        }
+        assert_eq!(LifetimeHistogram::bucket_bound(LIFETIME_BUCKETS - 1), None);
+    }
+
+    #[test]
+    fn test_size_buckets() {
+        assert_eq!(SizeHistogram::bucket(0), 0);
+        assert_eq!(SizeHistogram::bucket(1), 0);
+        assert_eq!(SizeHistogram::bucket(2), 1);
+        assert_eq!(SizeHistogram::bucket(3), 2);
+        assert_eq!(SizeHistogram::bucket(16), 4);
+        assert_eq!(SizeHistogram::bucket(17), 5);
+        assert_eq!(SizeHistogram::bucket(1 << 20), 20);
+        assert_eq!(SizeHistogram::bucket(usize::MAX), SIZE_BUCKETS - 1);
+        assert_eq!(SizeHistogram::bucket_label(4), "<=16 B");
+        assert_eq!(SizeHistogram::bucket_label(20), "<=1 MB");
+        assert_eq!(SizeHistogram::bucket_bound(SIZE_BUCKETS - 1), None);
+    }
+}
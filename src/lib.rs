@@ -1,79 +1,336 @@
 #![doc = include_str!("../README.md")]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
-use dashmap::DashMap;
-#[allow(unused_imports)]
 use std::collections::HashMap;
 use std::{
     alloc::{GlobalAlloc, Layout},
     cell::Cell,
     collections::BTreeMap,
     fmt,
-    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::{Instant, SystemTime},
 };
 
+#[cfg(any(feature = "allocator-api2", feature = "nightly"))]
+mod allocator;
+mod analysis;
+#[cfg(any(feature = "allocator-api2", feature = "nightly"))]
+pub use allocator::TrackingAllocator;
+#[cfg(feature = "backtrace")]
+pub use analysis::RecordedBacktraceReport;
+pub use analysis::RecordingAnalysis;
 #[cfg(feature = "backtrace")]
 mod backtrace_support;
+mod budget;
 #[cfg(feature = "backtrace")]
 use backtrace_support::*;
 #[cfg(feature = "backtrace")]
 pub use backtrace_support::{BacktraceMetric, BacktraceReport, HashedBacktrace};
+pub use budget::{remove_budget, set_budget, Budget, BudgetExceeded, BudgetScope};
 
-/// next thread id incrementor
-static THREAD_ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
-
-/// On linux you can check your system by running `cat /proc/sys/kernel/threads-max`
-/// It's almost certain that this limit will be hit in some strange corner cases.
-const MAX_THREADS: usize = 1024;
+mod histogram;
+mod live;
+#[cfg(all(target_os = "linux", feature = "fs"))]
+mod memory_report;
+#[cfg(all(target_os = "linux", feature = "fs"))]
+pub use memory_report::{process_memory_report, ProcessMemoryReport};
+mod observer;
+pub use observer::{AllocEvent, AllocObserver};
+mod recording;
+mod replay;
+pub use recording::{start_recording, stop_recording, Record, RecordingReader};
+pub use replay::ReplayStats;
+mod scope;
+mod snapshot;
+mod tags;
+mod task;
+mod thread_store;
+#[cfg(feature = "tracing")]
+mod tracing_support;
+mod tracker;
+pub use histogram::{LifetimeHistogram, SizeHistogram, LIFETIME_BUCKETS, SIZE_BUCKETS};
+pub use live::{live_allocations, visit_live_allocations, LiveAllocation};
+#[cfg(feature = "backtrace")]
+pub use scope::ScopeMetric;
+pub use scope::{track_scope, track_thread_scope, ScopeReport};
+pub use snapshot::{snapshot, Snapshot, SnapshotDiff, ThreadDelta};
+#[cfg(feature = "backtrace")]
+pub use snapshot::{BacktraceDelta, BacktraceDiffReport};
+use tags::current_tag;
+pub use tags::{
+    push_tag, tag_report, with_tag, TagGuard, TagMetric, TagReport, TAG_PATH_SEPARATOR,
+};
+use task::current_task;
+pub use task::{task_report, TaskReport, TrackAllocs, Tracked};
+use thread_store::*;
+#[cfg(feature = "tracing")]
+use tracing_support::{current_span, span_instance_alloc, span_instance_free};
+#[cfg(feature = "tracing")]
+pub use tracing_support::{span_report, AllocTrackLayer, SpanReport};
+use tracker::TrackerMaps;
+pub use tracker::{Tracker, GLOBAL_TRACKER};
+mod watch;
+use watch::deliver_events;
+pub use watch::{unwatch, watch, WatchCondition, WatchEvent, WatcherId};
 
 #[derive(Clone, Copy, Debug)]
 struct PointerData {
-    alloc_thread_id: usize,
+    size: usize,
+    align: usize,
+    /// `now_nanos` at the time of allocation, kept across reallocations
+    allocated_at: u64,
+    alloc_thread_slot: usize,
+    alloc_thread_uid: usize,
+    /// Innermost tag at the time of allocation, see `with_tag`
+    tag: usize,
+    /// Task polled at the time of allocation, see `TrackAllocs`
+    task: usize,
+    /// Innermost span entered at the time of allocation, see `AllocTrackLayer`
+    #[cfg(feature = "tracing")]
+    span: usize,
     #[cfg(feature = "backtrace")]
     trace_hash: u64,
+    /// Number of allocations this allocation stands for in the trace map of its tracker, 0.0 if it has no backtrace.
+    #[cfg(feature = "backtrace")]
+    sample_weight: f64,
 }
 
-lazy_static::lazy_static! {
-    /// pointer -> data
-    static ref PTR_MAP: DashMap<usize, PointerData> = DashMap::new();
-    // backtrace -> current allocation size
-    #[cfg(feature = "backtrace")]
-    static ref TRACE_MAP: DashMap<u64, TraceInfo> = DashMap::new();
-}
-
-/// Representation of globally-accessible TLS
-struct ThreadStore {
-    #[allow(dead_code)]
-    tid: AtomicU32,
-    alloc: AtomicUsize,
-    free: [AtomicUsize; MAX_THREADS],
-}
-
-/// A layout compatible representation of `ThreadStore` that is `Copy`.
-/// Layout of `AtomicX` is guaranteed to be identical to `X`
-/// Used to initialize arrays.
-#[allow(dead_code)]
-#[derive(Clone, Copy)]
-struct ThreadStoreLocal {
-    tid: u32,
-    alloc: usize,
-    free: [usize; MAX_THREADS],
-}
-
-/// Custom psuedo-TLS implementation that allows safe global introspection
-static THREAD_STORE: [ThreadStore; MAX_THREADS] = unsafe {
-    std::mem::transmute(
-        [ThreadStoreLocal {
-            tid: 0,
-            alloc: 0,
-            free: [0usize; MAX_THREADS],
-        }; MAX_THREADS],
-    )
-};
+impl PointerData {
+    /// `AllocObserver` event for this allocation at `ptr` with `layout`, made by the current thread
+    fn event(&self, ptr: *mut u8, layout: Layout) -> AllocEvent {
+        let thread = current_thread();
+        AllocEvent {
+            ptr,
+            layout,
+            thread_slot: thread.slot,
+            thread_id: thread.uid,
+            #[cfg(feature = "backtrace")]
+            trace_hash: self.recorded_trace(),
+        }
+    }
+
+    /// Hash of the backtrace this allocation is accounted to, if it has one
+    fn recorded_trace(&self) -> Option<u64> {
+        #[cfg(feature = "backtrace")]
+        return (self.sample_weight > 0.0).then_some(self.trace_hash);
+        #[cfg(not(feature = "backtrace"))]
+        None
+    }
+}
+
+/// Counters of live allocations and their high-water marks
+struct LiveCounters {
+    bytes: AtomicUsize,
+    allocations: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    peak_allocations: AtomicUsize,
+}
+
+/// A non-atomic copy of `LiveCounters`
+#[derive(Clone, Copy, Default, Debug)]
+struct LiveUsage {
+    bytes: usize,
+    allocations: usize,
+    peak_bytes: usize,
+    peak_allocations: usize,
+}
+
+impl LiveCounters {
+    const fn new() -> Self {
+        Self {
+            bytes: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            peak_bytes: AtomicUsize::new(0),
+            peak_allocations: AtomicUsize::new(0),
+        }
+    }
+
+    fn add(&self, bytes: usize, allocations: usize) {
+        let live_bytes = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak_bytes.fetch_max(live_bytes, Ordering::Relaxed);
+        let live_allocations =
+            self.allocations.fetch_add(allocations, Ordering::Relaxed) + allocations;
+        self.peak_allocations
+            .fetch_max(live_allocations, Ordering::Relaxed);
+    }
+
+    fn remove(&self, bytes: usize, allocations: usize) {
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.allocations.fetch_sub(allocations, Ordering::Relaxed);
+    }
+
+    fn reset_peaks(&self) {
+        self.peak_bytes
+            .store(self.bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.peak_allocations
+            .store(self.allocations.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    fn load(&self) -> LiveUsage {
+        LiveUsage {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            peak_bytes: self.peak_bytes.load(Ordering::Relaxed),
+            peak_allocations: self.peak_allocations.load(Ordering::Relaxed),
+        }
+    }
+
+    fn take(&self) -> LiveUsage {
+        LiveUsage {
+            bytes: self.bytes.swap(0, Ordering::Relaxed),
+            allocations: self.allocations.swap(0, Ordering::Relaxed),
+            peak_bytes: self.peak_bytes.swap(0, Ordering::Relaxed),
+            peak_allocations: self.peak_allocations.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    /// Add the live usage of `other`, keeping the higher of both peaks
+    fn merge(&self, other: &LiveUsage) {
+        self.bytes.fetch_add(other.bytes, Ordering::Relaxed);
+        self.allocations
+            .fetch_add(other.allocations, Ordering::Relaxed);
+        self.peak_bytes
+            .fetch_max(other.peak_bytes, Ordering::Relaxed);
+        self.peak_allocations
+            .fetch_max(other.peak_allocations, Ordering::Relaxed);
+    }
+}
+
+impl LiveUsage {
+    fn remove(&mut self, bytes: usize, allocations: usize) {
+        self.bytes = self.bytes.saturating_sub(bytes);
+        self.allocations = self.allocations.saturating_sub(allocations);
+    }
+
+    fn reset_peaks(&mut self) {
+        self.peak_bytes = self.bytes;
+        self.peak_allocations = self.allocations;
+    }
+
+    fn peak(&self) -> PeakMetric {
+        PeakMetric {
+            bytes: self.peak_bytes as u64,
+            allocations: self.peak_allocations as u64,
+        }
+    }
+}
+
+/// High-water mark of live memory, since the start of the process or the last call to `reset_peaks`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeakMetric {
+    /// Highest number of bytes allocated and not freed at once
+    pub bytes: u64,
+    /// Highest number of allocations not freed at once
+    pub allocations: u64,
+}
+
+impl PeakMetric {
+    pub(crate) fn max(&mut self, other: &PeakMetric) {
+        self.bytes = self.bytes.max(other.bytes);
+        self.allocations = self.allocations.max(other.allocations);
+    }
+}
+
+impl fmt::Display for PeakMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} allocations)", Size(self.bytes), self.allocations)
+    }
+}
+
+/// Process-wide peak of tracked live memory, see `Tracker::process_peak`
+pub fn process_peak() -> PeakMetric {
+    GLOBAL_TRACKER.process_peak()
+}
+
+/// Summary of the allocations of a tracker, see `global_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GlobalStats {
+    /// Total bytes allocated
+    pub total_allocated: u64,
+    /// Total bytes freed
+    pub total_freed: u64,
+    /// Bytes currently allocated and not freed
+    pub live_bytes: u64,
+    /// Number of allocations currently not freed
+    pub live_allocations: u64,
+    /// Peak of `live_bytes` and `live_allocations`
+    pub peak: PeakMetric,
+}
+
+impl fmt::Display for GlobalStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "total_allocated: {}", Size(self.total_allocated))?;
+        writeln!(f, "total_freed: {}", Size(self.total_freed))?;
+        writeln!(f, "live_bytes: {}", Size(self.live_bytes))?;
+        writeln!(f, "live_allocations: {}", self.live_allocations)?;
+        writeln!(f, "peak_used: {}", self.peak)?;
+        Ok(())
+    }
+}
+
+/// Get process-wide allocation counters, see `Tracker::global_stats`
+pub fn global_stats() -> GlobalStats {
+    GLOBAL_TRACKER.global_stats()
+}
+
+/// Process-wide histogram of the sizes of all allocations and reallocations, see `Tracker::size_histogram`
+pub fn size_histogram() -> SizeHistogram {
+    GLOBAL_TRACKER.size_histogram()
+}
+
+/// Reset all process-wide, per-thread and per-backtrace peaks to current usage, see `Tracker::reset_peaks`
+pub fn reset_peaks() {
+    GLOBAL_TRACKER.reset_peaks();
+}
+
+impl Tracker {
+    /// Account a tracked allocation as freed. Must be called with `IN_ALLOC` set.
+    fn account_free(&'static self, maps: &TrackerMaps, target: &PointerData, size: usize) {
+        let lifetime_nanos = now_nanos().saturating_sub(target.allocated_at);
+        #[cfg(feature = "backtrace")]
+        if target.sample_weight > 0.0 {
+            let in_use = maps.trace_map.get_mut(&target.trace_hash).map(|mut info| {
+                info.free(size, target.sample_weight, lifetime_nanos);
+                info.in_use()
+            });
+            if let Some(in_use) = in_use {
+                self.check_trace_watchers(target.trace_hash, in_use);
+            }
+        }
+        self.threads.free(
+            target.alloc_thread_slot,
+            target.alloc_thread_uid,
+            size,
+            Some(lifetime_nanos),
+        );
+        maps.tags.free(target.tag, size);
+        maps.tasks.free(target.task, size);
+        #[cfg(feature = "tracing")]
+        {
+            maps.spans.free(target.span, size);
+            span_instance_free(size);
+        }
+        self.live.remove(size, 1);
+        self.total_freed.fetch_add(size, Ordering::Relaxed);
+        self.check_live_watchers();
+    }
+}
+
+/// Nanoseconds since tracking started, used to timestamp allocations
+fn now_nanos() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
 
 thread_local! {
-    static THREAD_ID: usize = THREAD_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
     /// Used to avoid recursive alloc/dealloc calls for interior allocation
-    static IN_ALLOC: Cell<bool> = Cell::new(false);
+    static IN_ALLOC: Cell<bool> = const { Cell::new(false) };
 }
 
 fn enter_alloc<T>(func: impl FnOnce() -> T) -> T {
@@ -84,105 +341,554 @@ fn enter_alloc<T>(func: impl FnOnce() -> T) -> T {
     output
 }
 
-#[derive(Default, Clone, Copy, Debug, PartialEq)]
+/// Runs `func` without tracking its allocations, then copies its output into tracked memory
+fn untracked<T: Clone>(func: impl FnOnce() -> T) -> T {
+    let output = enter_alloc(func);
+    let tracked = output.clone();
+    enter_alloc(move || drop(output));
+    tracked
+}
+
+/// Owner of a value allocated with `IN_ALLOC` set that may be dropped outside of the allocator, i.e. by a thread local
+/// destructor. The value is dropped with `IN_ALLOC` set too, so its frees are not mistaken for frees of tracked blocks.
+pub(crate) struct Untracked<T>(ManuallyDrop<T>);
+
+impl<T> Untracked<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self(ManuallyDrop::new(value))
+    }
+}
+
+impl<T> Deref for Untracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Untracked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> Drop for Untracked<T> {
+    fn drop(&mut self) {
+        enter_alloc(|| unsafe { ManuallyDrop::drop(&mut self.0) });
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
 pub enum BacktraceMode {
     #[default]
     /// Report no backtraces
-    None,
+    None = 0,
     #[cfg(feature = "backtrace")]
     /// Report backtraces with unuseful entries removed (i.e. alloc_track, allocator internals)
-    Short,
+    Short = 1,
     /// Report the full backtrace
     #[cfg(feature = "backtrace")]
-    Full,
+    Full = 2,
+}
+
+impl BacktraceMode {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(BacktraceMode::None),
+            #[cfg(feature = "backtrace")]
+            1 => Some(BacktraceMode::Short),
+            #[cfg(feature = "backtrace")]
+            2 => Some(BacktraceMode::Full),
+            _ => None,
+        }
+    }
+}
+
+/// Override the `BacktraceMode` of the global tracker at runtime, see `Tracker::set_backtrace_mode`
+pub fn set_backtrace_mode(mode: BacktraceMode) {
+    GLOBAL_TRACKER.set_backtrace_mode(mode);
+}
+
+/// Remove any override set by `set_backtrace_mode`, going back to the mode passed to `AllocTrack::new`.
+pub fn reset_backtrace_mode() {
+    GLOBAL_TRACKER.reset_backtrace_mode();
+}
+
+/// Pause all tracking by the global tracker, see `Tracker::pause`
+pub fn pause() {
+    GLOBAL_TRACKER.pause();
+}
+
+/// Resume tracking after a call to `pause`.
+pub fn resume() {
+    GLOBAL_TRACKER.resume();
+}
+
+/// Returns true if tracking by the global tracker is paused
+pub fn is_paused() -> bool {
+    GLOBAL_TRACKER.is_paused()
+}
+
+/// Details of an allocation or reallocation the inner allocator failed to satisfy, passed to the OOM hook.
+pub struct AllocFailure<'a> {
+    /// Requested layout. For reallocations, this is the layout of the requested new size.
+    pub layout: Layout,
+    /// Whether this was a reallocation
+    pub realloc: bool,
+    /// alloc-track id of the allocating thread
+    pub thread_id: usize,
+    /// OS id of the allocating thread, 0 if unavailable
+    pub os_thread_id: u32,
+    /// Backtrace of the failed allocation, if backtraces are enabled
+    #[cfg(feature = "backtrace")]
+    pub backtrace: Option<&'a HashedBacktrace>,
+    #[cfg(not(feature = "backtrace"))]
+    _marker: std::marker::PhantomData<&'a ()>,
+}
+
+/// Register a hook called whenever an allocator using the global tracker fails to allocate, see `Tracker::set_oom_hook`
+pub fn set_oom_hook(hook: fn(&AllocFailure)) {
+    GLOBAL_TRACKER.set_oom_hook(hook);
+}
+
+/// Unregister the hook registered with `set_oom_hook`, if any.
+pub fn clear_oom_hook() {
+    GLOBAL_TRACKER.clear_oom_hook();
 }
 
 /// Global memory allocator wrapper that can track per-thread and per-backtrace memory usage.
 pub struct AllocTrack<T: GlobalAlloc> {
     inner: T,
-    backtrace: BacktraceMode,
+    config: TrackConfig,
 }
 
 impl<T: GlobalAlloc> AllocTrack<T> {
     pub const fn new(inner: T, backtrace: BacktraceMode) -> Self {
-        Self { inner, backtrace }
+        Self {
+            inner,
+            config: TrackConfig::new(backtrace),
+        }
+    }
+
+    /// Keep the books of this allocator in `tracker` rather than `GLOBAL_TRACKER`, to report on it separately.
+    /// Several allocators may share a tracker.
+    pub const fn with_tracker(mut self, tracker: &'static Tracker) -> Self {
+        self.config.tracker = tracker;
+        self
+    }
+
+    /// The tracker keeping the books of this allocator
+    pub fn tracker(&self) -> &'static Tracker {
+        self.config.tracker
+    }
+
+    /// Only capture a backtrace on average once every `sample_interval` allocated bytes, rather than for every allocation.
+    /// Sampled allocations are scaled up so that backtrace metrics remain unbiased estimates.
+    /// A `sample_interval` of 0 disables sampling.
+    pub const fn with_sample_interval(mut self, sample_interval: usize) -> Self {
+        self.config.sample_interval = sample_interval;
+        self
+    }
+
+    /// Call `observer` on every tracked allocation, free and reallocation, see `AllocObserver`
+    pub const fn with_observer(mut self, observer: &'static dyn AllocObserver) -> Self {
+        self.config.observer = Some(observer);
+        self
+    }
+
+    /// The backtrace mode currently in effect, taking `set_backtrace_mode` into account
+    pub fn backtrace_mode(&self) -> BacktraceMode {
+        self.config.backtrace_mode()
     }
-}
-#[cfg(all(unix, feature = "fs"))]
-#[inline(always)]
-unsafe fn get_sys_tid() -> u32 {
-    libc::syscall(libc::SYS_gettid) as u32
 }
 
-#[cfg(all(windows, feature = "fs"))]
-#[inline(always)]
-unsafe fn get_sys_tid() -> u32 {
-    windows::Win32::System::Threading::GetCurrentThreadId()
+/// Settings of a tracking allocator wrapper, and the accounting of its allocations.
+/// Shared by `AllocTrack` and `TrackingAllocator`, which only differ in how they call the inner allocator.
+#[derive(Clone)]
+pub(crate) struct TrackConfig {
+    backtrace: BacktraceMode,
+    #[cfg_attr(not(feature = "backtrace"), allow(dead_code))]
+    sample_interval: usize,
+    tracker: &'static Tracker,
+    observer: Option<&'static dyn AllocObserver>,
 }
 
-unsafe impl<T: GlobalAlloc> GlobalAlloc for AllocTrack<T> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl TrackConfig {
+    const fn new(backtrace: BacktraceMode) -> Self {
+        Self {
+            backtrace,
+            sample_interval: 0,
+            tracker: &GLOBAL_TRACKER,
+            observer: None,
+        }
+    }
+
+    fn backtrace_mode(&self) -> BacktraceMode {
+        self.tracker
+            .backtrace_mode_override()
+            .unwrap_or(self.backtrace)
+    }
+
+    /// Account an allocation the inner allocator failed to satisfy and call the OOM hook.
+    /// Must be called with `IN_ALLOC` set.
+    fn alloc_failed(&self, layout: Layout, realloc: bool) {
+        let (thread, slot) = self.tracker.threads.current_slot();
+        slot.failed_allocs.fetch_add(1, Ordering::Relaxed);
+        slot.failed_bytes
+            .fetch_add(layout.size(), Ordering::Relaxed);
+        #[cfg(feature = "backtrace")]
+        let backtrace_mode = self.backtrace_mode();
+        #[cfg(feature = "backtrace")]
+        let trace = match backtrace_mode {
+            BacktraceMode::None => None,
+            _ => Some(HashedBacktrace::capture(backtrace_mode)),
+        };
+        #[cfg(feature = "backtrace")]
+        if let Some(trace) = &trace {
+            let mut trace_info = self
+                .tracker
+                .maps()
+                .trace_map
+                .entry(trace.hash())
+                .or_insert_with(|| {
+                    TraceInfo::new(trace.clone(), backtrace_mode, self.sample_interval)
+                });
+            trace_info.failed_allocs += 1;
+            trace_info.failed_bytes += layout.size() as u64;
+        }
+        if let Some(hook) = self.tracker.oom_hook() {
+            hook(&AllocFailure {
+                layout,
+                realloc,
+                thread_id: thread.uid,
+                os_thread_id: slot.tid.load(Ordering::Relaxed),
+                #[cfg(feature = "backtrace")]
+                backtrace: trace.as_ref(),
+                #[cfg(not(feature = "backtrace"))]
+                _marker: std::marker::PhantomData,
+            });
+        }
+    }
+
+    /// Allocate with `alloc`, which returns null on failure, and account the allocation
+    fn alloc(&self, layout: Layout, alloc: impl FnOnce() -> *mut u8) -> *mut u8 {
         if IN_ALLOC.with(|x| x.get()) {
-            return self.inner.alloc(layout);
+            // i.e. by an observer, which may free it outside the allocator
+            let ptr = alloc();
+            if !ptr.is_null() {
+                self.tracker.add_untracked_block();
+            }
+            return ptr;
         }
-        enter_alloc(|| {
+        if self.tracker.is_paused() {
+            let ptr = enter_alloc(alloc);
+            if !ptr.is_null() {
+                self.tracker.add_untracked_block();
+            }
+            return ptr;
+        }
+        let ptr = enter_alloc(|| {
+            let tracker = self.tracker;
+            let maps = tracker.maps();
             let size = layout.size();
-            let ptr = self.inner.alloc(layout);
-            let tid = THREAD_ID.with(|x| *x);
-            assert!(
-                tid < MAX_THREADS,
-                "Thread ID {tid} is greater than the maximum number of threads {MAX_THREADS}"
-            );
-            #[cfg(feature = "fs")]
-            if THREAD_STORE[tid].tid.load(Ordering::Relaxed) == 0 {
-                let os_tid = get_sys_tid();
-                THREAD_STORE[tid].tid.store(os_tid, Ordering::Relaxed);
+            let tag = current_tag();
+            if !tracker.check_budgets(maps, layout, size, size, tag) {
+                self.alloc_failed(layout, false);
+                return null_mut();
+            }
+            let ptr = alloc();
+            if ptr.is_null() {
+                self.alloc_failed(layout, false);
+                return ptr;
+            }
+            let thread = tracker.threads.alloc(size);
+            tracker.live.add(size, 1);
+            tracker.total_allocated.fetch_add(size, Ordering::Relaxed);
+            tracker.sizes.record_size(size);
+            maps.tags.alloc(tag, size);
+            let task = current_task();
+            maps.tasks.alloc(task, size);
+            #[cfg(feature = "tracing")]
+            let span = current_span();
+            #[cfg(feature = "tracing")]
+            {
+                maps.spans.alloc(span, size);
+                span_instance_alloc(size, 1);
             }
-            THREAD_STORE[tid].alloc.fetch_add(size, Ordering::Relaxed);
             #[cfg(feature = "backtrace")]
-            let trace = HashedBacktrace::capture(self.backtrace);
-            PTR_MAP.insert(
-                ptr as usize,
-                PointerData {
-                    alloc_thread_id: tid,
-                    #[cfg(feature = "backtrace")]
-                    trace_hash: trace.hash(),
-                },
-            );
+            let backtrace_mode = self.backtrace_mode();
             #[cfg(feature = "backtrace")]
-            if !matches!(self.backtrace, BacktraceMode::None) {
-                let mut trace_info = TRACE_MAP.entry(trace.hash()).or_insert_with(|| TraceInfo {
-                    backtrace: trace,
-                    allocated: 0,
-                    freed: 0,
-                    mode: self.backtrace,
-                    allocations: 0,
-                });
-                trace_info.allocated += size as u64;
-                trace_info.allocations += 1;
+            let sample_weight = match backtrace_mode {
+                BacktraceMode::None => None,
+                _ if self.sample_interval == 0 => Some(1.0),
+                _ => sample_allocation(
+                    tracker as *const Tracker as usize,
+                    size,
+                    self.sample_interval,
+                ),
+            };
+            #[cfg(feature = "backtrace")]
+            let trace = match sample_weight {
+                Some(_) => HashedBacktrace::capture(backtrace_mode),
+                None => HashedBacktrace::capture(BacktraceMode::None),
+            };
+            let target = PointerData {
+                size,
+                align: layout.align(),
+                allocated_at: now_nanos(),
+                alloc_thread_slot: thread.slot,
+                alloc_thread_uid: thread.uid,
+                tag,
+                task,
+                #[cfg(feature = "tracing")]
+                span,
+                #[cfg(feature = "backtrace")]
+                trace_hash: trace.hash(),
+                #[cfg(feature = "backtrace")]
+                sample_weight: sample_weight.unwrap_or(0.0),
+            };
+            maps.ptr_map.insert(ptr as usize, target);
+            #[cfg(feature = "backtrace")]
+            if let Some(sample_weight) = sample_weight {
+                let trace_hash = target.trace_hash;
+                let mut trace_info = maps
+                    .trace_map
+                    .entry(trace_hash)
+                    .or_insert_with(|| TraceInfo::new(trace, backtrace_mode, self.sample_interval));
+                trace_info.alloc(size, sample_weight);
+                let in_use = trace_info.in_use();
+                drop(trace_info);
+                tracker.check_trace_watchers(trace_hash, in_use);
+            }
+            tracker.record(Record::Alloc {
+                time: target.allocated_at,
+                ptr: ptr as u64,
+                size: size as u64,
+                align: layout.align() as u64,
+                thread_id: thread.uid as u64,
+                trace_hash: target.recorded_trace(),
+            });
+            if let Some(observer) = self.observer {
+                observer.on_alloc(&target.event(ptr, layout));
             }
+            tracker.check_live_watchers();
             ptr
-        })
+        });
+        deliver_events();
+        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    /// Free `ptr` with `dealloc` and account the free
+    fn dealloc(&self, ptr: *mut u8, layout: Layout, dealloc: impl FnOnce()) {
+        self.release(ptr, layout, || {
+            dealloc();
+            true
+        });
+    }
+
+    /// Give up `ptr` with `release`, which returns false and leaves the block untouched on failure, and account the free on success
+    fn release(&self, ptr: *mut u8, layout: Layout, release: impl FnOnce() -> bool) {
         if IN_ALLOC.with(|x| x.get()) {
-            self.inner.dealloc(ptr, layout);
+            if release() {
+                self.tracker.remove_untracked_block();
+            }
             return;
         }
         enter_alloc(|| {
+            let maps = self.tracker.maps();
+            let Some((_, target)) = maps.ptr_map.remove(&(ptr as usize)) else {
+                self.tracker.free_untracked_block(ptr);
+                if !release() {
+                    self.tracker.add_untracked_block();
+                }
+                return;
+            };
+            // timestamped before freeing, so that it precedes any reuse of the address by another thread
+            let time = now_nanos();
+            if !release() {
+                maps.ptr_map.insert(ptr as usize, target);
+                return;
+            }
+            self.freed(maps, ptr, layout, &target, time);
+        });
+        deliver_events();
+    }
+
+    /// Account the free of a tracked block, timestamped at `time` before it was freed.
+    /// Must be called with `IN_ALLOC` set.
+    fn freed(
+        &self,
+        maps: &TrackerMaps,
+        ptr: *mut u8,
+        layout: Layout,
+        target: &PointerData,
+        time: u64,
+    ) {
+        self.tracker.account_free(maps, target, layout.size());
+        self.tracker.record(Record::Free {
+            time,
+            ptr: ptr as u64,
+            size: layout.size() as u64,
+            thread_id: current_thread().uid as u64,
+        });
+        if let Some(observer) = self.observer {
+            observer.on_dealloc(&target.event(ptr, layout));
+        }
+    }
+
+    /// Move `ptr` from `layout` to `new_layout` with `realloc`, which returns null on failure, and account the reallocation
+    fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_layout: Layout,
+        realloc: impl FnOnce() -> *mut u8,
+    ) -> *mut u8 {
+        if IN_ALLOC.with(|x| x.get()) {
+            return realloc();
+        }
+        let new_ptr = enter_alloc(|| {
+            let tracker = self.tracker;
+            let maps = tracker.maps();
             let size = layout.size();
-            let (_, target) = PTR_MAP.remove(&(ptr as usize)).expect("double free");
+            let new_size = new_layout.size();
+            // removed before reallocating, as the old address may be handed out to another thread as soon as it is freed
+            let Some((_, mut target)) = maps.ptr_map.remove(&(ptr as usize)) else {
+                // allocated while paused
+                return realloc();
+            };
+            if tracker.is_paused() {
+                // account as freed, the reallocated block is not tracked
+                let time = now_nanos();
+                let new_ptr = realloc();
+                if new_ptr.is_null() {
+                    // the original allocation is left untouched
+                    maps.ptr_map.insert(ptr as usize, target);
+                    return new_ptr;
+                }
+                self.freed(maps, ptr, layout, &target, time);
+                tracker.add_untracked_block();
+                return new_ptr;
+            }
+            // a block reallocated by another thread than its owner moves to the current thread as a whole
+            let thread_grown = if target.alloc_thread_uid == current_thread().uid {
+                new_size.saturating_sub(size)
+            } else {
+                new_size
+            };
+            let denied = !tracker.check_budgets(
+                maps,
+                new_layout,
+                new_size.saturating_sub(size),
+                thread_grown,
+                target.tag,
+            );
+            // timestamped before reallocating, so that it precedes any reuse of the old address by another thread
+            let released_at = now_nanos();
+            let new_ptr = if denied { null_mut() } else { realloc() };
+            if new_ptr.is_null() {
+                // the original allocation is left untouched
+                maps.ptr_map.insert(ptr as usize, target);
+                self.alloc_failed(new_layout, true);
+                return new_ptr;
+            }
+            let moved = new_ptr != ptr;
+            let owner = tracker.threads.realloc(
+                target.alloc_thread_slot,
+                target.alloc_thread_uid,
+                size,
+                new_size,
+                moved,
+            );
+            tracker.sizes.record_size(new_size);
+            maps.tags.realloc(target.tag, size, new_size);
+            maps.tasks.realloc(target.task, size, new_size);
+            #[cfg(feature = "tracing")]
+            {
+                maps.spans.realloc(target.span, size, new_size);
+                if new_size >= size {
+                    span_instance_alloc(new_size - size, 0);
+                } else {
+                    span_instance_free(size - new_size);
+                }
+            }
+            target.size = new_size;
+            target.align = new_layout.align();
+            target.alloc_thread_slot = owner.slot;
+            target.alloc_thread_uid = owner.uid;
+            if new_size >= size {
+                tracker.live.add(new_size - size, 0);
+                tracker
+                    .total_allocated
+                    .fetch_add(new_size - size, Ordering::Relaxed);
+            } else {
+                tracker.live.remove(size - new_size, 0);
+                tracker
+                    .total_freed
+                    .fetch_add(size - new_size, Ordering::Relaxed);
+            }
             #[cfg(feature = "backtrace")]
-            if !matches!(self.backtrace, BacktraceMode::None) {
-                if let Some(mut info) = TRACE_MAP.get_mut(&target.trace_hash) {
-                    info.freed += size as u64;
+            if target.sample_weight > 0.0 {
+                let in_use = maps.trace_map.get_mut(&target.trace_hash).map(|mut info| {
+                    info.realloc(size, new_size, moved, target.sample_weight);
+                    info.in_use()
+                });
+                if let Some(in_use) = in_use {
+                    tracker.check_trace_watchers(target.trace_hash, in_use);
                 }
             }
-            self.inner.dealloc(ptr, layout);
-            let tid = THREAD_ID.with(|x| *x);
-            THREAD_STORE[tid].free[target.alloc_thread_id].fetch_add(size, Ordering::SeqCst);
+            tracker.check_live_watchers();
+            maps.ptr_map.insert(new_ptr as usize, target);
+            tracker.record(Record::Realloc {
+                time: now_nanos(),
+                released_at,
+                old_ptr: ptr as u64,
+                ptr: new_ptr as u64,
+                old_size: size as u64,
+                size: new_size as u64,
+                align: new_layout.align() as u64,
+                thread_id: current_thread().uid as u64,
+                trace_hash: target.recorded_trace(),
+            });
+            if let Some(observer) = self.observer {
+                observer.on_realloc(ptr, layout, &target.event(new_ptr, new_layout));
+            }
+            new_ptr
         });
+        deliver_events();
+        new_ptr
+    }
+}
+
+#[cfg(all(unix, feature = "fs"))]
+#[inline(always)]
+unsafe fn get_sys_tid() -> u32 {
+    libc::syscall(libc::SYS_gettid) as u32
+}
+
+#[cfg(all(windows, feature = "fs"))]
+#[inline(always)]
+unsafe fn get_sys_tid() -> u32 {
+    windows::Win32::System::Threading::GetCurrentThreadId()
+}
+
+unsafe impl<T: GlobalAlloc> GlobalAlloc for AllocTrack<T> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.config.alloc(layout, || self.inner.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.config
+            .dealloc(ptr, layout, || self.inner.dealloc(ptr, layout));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        self.config.realloc(ptr, layout, new_layout, || {
+            self.inner.realloc(ptr, layout, new_size)
+        })
     }
 }
 
@@ -216,6 +922,52 @@ impl fmt::Display for SizeF64 {
     }
 }
 
+/// Reallocation statistics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReallocMetric {
+    /// Number of reallocations
+    pub reallocs: u64,
+    /// Number of reallocations that could not grow or shrink in place and moved the allocation
+    pub moves: u64,
+    /// Total bytes added by growing reallocations
+    pub grown: u64,
+    /// Total bytes removed by shrinking reallocations
+    pub shrunk: u64,
+}
+
+impl ReallocMetric {
+    /// Number of reallocations that grew or shrank in place
+    pub fn in_place(&self) -> u64 {
+        self.reallocs.saturating_sub(self.moves)
+    }
+
+    pub(crate) fn add(&mut self, other: &ReallocMetric) {
+        self.reallocs += other.reallocs;
+        self.moves += other.moves;
+        self.grown += other.grown;
+        self.shrunk += other.shrunk;
+    }
+}
+
+/// Writes nothing if there were no reallocations
+impl fmt::Display for ReallocMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.reallocs == 0 {
+            return Ok(());
+        }
+        writeln!(
+            f,
+            "reallocs: {} ({} in place, {} moved)",
+            self.reallocs,
+            self.in_place(),
+            self.moves
+        )?;
+        writeln!(f, "realloc_grown: {}", Size(self.grown))?;
+        writeln!(f, "realloc_shrunk: {}", Size(self.shrunk))?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ThreadMetric {
     /// Total bytes allocated in this thread
@@ -228,6 +980,22 @@ pub struct ThreadMetric {
     pub current_used: u64,
     /// Total bytes allocated in this thread that have been freed by the given thread
     pub freed_by_others: BTreeMap<String, u64>,
+    /// When this thread exited, if it has
+    pub exited_at: Option<SystemTime>,
+    /// Reallocations done by this thread
+    pub realloc: ReallocMetric,
+    /// Number of allocations or reallocations by this thread that the inner allocator failed to satisfy
+    pub failed_allocs: u64,
+    /// Bytes requested by failed allocations or reallocations of this thread
+    pub failed_bytes: u64,
+    /// Number of allocations made in this thread that are not freed
+    pub live_allocations: u64,
+    /// Peak of `current_used` and `live_allocations`. For threads sharing a name, this is the largest peak among them.
+    pub peak: PeakMetric,
+    /// How long allocations made in this thread lived before being freed
+    pub lifetimes: LifetimeHistogram,
+    /// Sizes of allocations and reallocations made in this thread
+    pub sizes: SizeHistogram,
 }
 
 impl fmt::Display for ThreadMetric {
@@ -236,6 +1004,25 @@ impl fmt::Display for ThreadMetric {
         writeln!(f, "total_did_free: {}", Size(self.total_did_free))?;
         writeln!(f, "total_freed: {}", Size(self.total_freed))?;
         writeln!(f, "current_used: {}", Size(self.current_used))?;
+        writeln!(f, "live_allocations: {}", self.live_allocations)?;
+        writeln!(f, "peak_used: {}", self.peak)?;
+        write!(f, "{}", self.realloc)?;
+        write!(f, "{}", self.sizes)?;
+        write!(f, "{}", self.lifetimes)?;
+        if self.failed_allocs != 0 {
+            writeln!(
+                f,
+                "failed_allocs: {} ({})",
+                self.failed_allocs,
+                Size(self.failed_bytes)
+            )?;
+        }
+        if let Some(exited_at) = self.exited_at {
+            let ago = SystemTime::now()
+                .duration_since(exited_at)
+                .unwrap_or_default();
+            writeln!(f, "exited: {:.01}s ago", ago.as_secs_f64())?;
+        }
         for (name, size) in &self.freed_by_others {
             writeln!(f, "freed by {}: {}", name, Size(*size))?;
         }
@@ -243,7 +1030,20 @@ impl fmt::Display for ThreadMetric {
     }
 }
 
+/// Name under which threads that have exited are reported once they are no longer retained individually
+pub const EXITED_THREADS_NAME: &str = "exited threads";
+
+/// Set how many exited threads `thread_report` lists individually, see `Tracker::set_exited_thread_retention`
+pub fn set_exited_thread_retention(limit: usize) {
+    GLOBAL_TRACKER.set_exited_thread_retention(limit);
+}
+
+fn exited_thread_name(name: &str, uid: usize) -> String {
+    format!("{name} [exited #{uid}]")
+}
+
 /// A comprehensive report of all thread allocation metrics
+#[derive(Clone)]
 pub struct ThreadReport(pub BTreeMap<String, ThreadMetric>);
 
 impl fmt::Display for ThreadReport {
@@ -255,33 +1055,82 @@ impl fmt::Display for ThreadReport {
     }
 }
 
-/// Generate a memory usage report for backtraces, if enabled
+/// Generate a memory usage report for backtraces of the global tracker, see `Tracker::backtrace_report`
 #[cfg(feature = "backtrace")]
 pub fn backtrace_report(
     filter: impl Fn(&crate::backtrace::Backtrace, &BacktraceMetric) -> bool,
 ) -> BacktraceReport {
-    IN_ALLOC.with(|x| x.set(true));
-    let mut out = vec![];
-    for mut entry in TRACE_MAP.iter_mut() {
-        let metric = BacktraceMetric {
-            allocated: entry.allocated,
-            freed: entry.freed,
-            mode: entry.mode,
-            allocations: entry.allocations,
-        };
-        if !filter(entry.backtrace.inner(), &metric) {
-            continue;
+    GLOBAL_TRACKER.backtrace_report(filter)
+}
+
+/// Look up and symbolize a backtrace of the global tracker, see `Tracker::resolve_backtrace`
+#[cfg(feature = "backtrace")]
+pub fn resolve_backtrace(hash: u64) -> Option<HashedBacktrace> {
+    GLOBAL_TRACKER.resolve_backtrace(hash)
+}
+
+#[cfg(feature = "backtrace")]
+impl Tracker {
+    /// Generate a memory usage report for backtraces, if enabled
+    pub fn backtrace_report(
+        &'static self,
+        filter: impl Fn(&crate::backtrace::Backtrace, &BacktraceMetric) -> bool,
+    ) -> BacktraceReport {
+        IN_ALLOC.with(|x| x.set(true));
+        let mut out = vec![];
+        for mut entry in self.maps().trace_map.iter_mut() {
+            let metric = entry.metric();
+            if !filter(entry.backtrace.inner(), &metric) {
+                continue;
+            }
+            entry.backtrace.inner_mut().resolve();
+            out.push((entry.backtrace.clone(), metric));
         }
-        entry.backtrace.inner_mut().resolve();
-        out.push((entry.backtrace.clone(), metric));
+        out.sort_by_key(|x| x.1.allocated.saturating_sub(x.1.freed) as i64);
+        IN_ALLOC.with(|x| x.set(false));
+        let out2 = out.clone();
+        IN_ALLOC.with(|x| x.set(true));
+        drop(out);
+        IN_ALLOC.with(|x| x.set(false));
+        BacktraceReport(out2)
+    }
+
+    /// Look up and symbolize the backtrace with the given hash, as found in `HashedBacktrace::hash`
+    pub fn resolve_backtrace(&'static self, hash: u64) -> Option<HashedBacktrace> {
+        untracked(|| {
+            let mut entry = self.maps().trace_map.get_mut(&hash)?;
+            entry.backtrace.inner_mut().resolve();
+            Some(entry.backtrace.clone())
+        })
+    }
+
+    /// Mode a backtrace was captured with
+    pub(crate) fn trace_mode(&'static self, hash: u64) -> Option<BacktraceMode> {
+        enter_alloc(|| self.maps().trace_map.get(&hash).map(|info| info.mode))
+    }
+}
+
+#[cfg(all(unix, feature = "fs"))]
+fn current_thread_name() -> Option<String> {
+    let tid = unsafe { get_sys_tid() };
+    std::fs::read_to_string(format!("/proc/self/task/{tid}/comm"))
+        .ok()
+        .map(|name| name.trim().to_string())
+}
+
+#[cfg(all(windows, feature = "fs"))]
+fn current_thread_name() -> Option<String> {
+    unsafe {
+        let handle = windows::Win32::System::Threading::GetCurrentThread();
+        windows::Win32::System::Threading::GetThreadDescription(handle)
+            .ok()
+            .and_then(|name| name.to_string().ok())
     }
-    out.sort_by_key(|x| x.1.allocated.saturating_sub(x.1.freed) as i64);
-    IN_ALLOC.with(|x| x.set(false));
-    let out2 = out.clone();
-    IN_ALLOC.with(|x| x.set(true));
-    drop(out);
-    IN_ALLOC.with(|x| x.set(false));
-    BacktraceReport(out2)
+}
+
+#[cfg(not(feature = "fs"))]
+fn current_thread_name() -> Option<String> {
+    None
 }
 
 #[cfg(all(unix, feature = "fs"))]
@@ -351,63 +1200,116 @@ fn os_tid_names() -> HashMap<u32, String> {
     os_tid_names
 }
 
-/// Generate a memory usage report
-/// Note that the numbers are not a synchronized snapshot, and have slight timing skew.
+/// Generate a memory usage report of the global tracker, see `Tracker::thread_report`
 pub fn thread_report() -> ThreadReport {
+    GLOBAL_TRACKER.thread_report()
+}
+
+/// Names of all live and retained exited threads of `threads` by thread id, must be called while in `IN_ALLOC`
+fn thread_uid_names(
+    threads: &ThreadStoreTable,
+    exited_threads: &BTreeMap<usize, ExitedThread>,
+) -> HashMap<usize, String> {
     #[cfg(feature = "fs")]
     let os_tid_names: HashMap<u32, String> = os_tid_names();
-    #[cfg(feature = "fs")]
-    let mut tid_names: HashMap<usize, &String> = HashMap::new();
-    #[cfg(feature = "fs")]
-    let get_tid_name = {
-        for (i, thread) in THREAD_STORE.iter().enumerate() {
-            let tid = thread.tid.load(Ordering::Relaxed);
-            if tid == 0 {
-                continue;
-            }
-            if let Some(name) = os_tid_names.get(&tid) {
-                tid_names.insert(i, name);
-            }
-        }
-        |id: usize| tid_names.get(&id).map(|x| &**x)
-    };
-    #[cfg(not(feature = "fs"))]
-    let get_tid_name = { move |id: usize| Some(id.to_string()) };
-
-    let mut metrics = BTreeMap::new();
-
-    for (i, thread) in THREAD_STORE.iter().enumerate() {
-        let Some(name) = get_tid_name(i) else {
+    let mut uid_names: HashMap<usize, String> = HashMap::new();
+    for i in 0..threads.len() {
+        let Some(thread) = threads.get(i) else {
             continue;
         };
-        let alloced = thread.alloc.load(Ordering::Relaxed) as u64;
-        let metric: &mut ThreadMetric = metrics.entry(name.into()).or_default();
-        metric.total_alloc += alloced;
+        let uid = thread.uid.load(Ordering::Acquire);
+        if uid == 0 {
+            continue;
+        }
+        #[cfg(feature = "fs")]
+        let name = os_tid_names
+            .get(&thread.tid.load(Ordering::Relaxed))
+            .cloned()
+            .unwrap_or_else(|| uid.to_string());
+        #[cfg(not(feature = "fs"))]
+        let name = uid.to_string();
+        uid_names.insert(uid, name);
+    }
+    for (uid, thread) in exited_threads {
+        uid_names.insert(*uid, exited_thread_name(&thread.name, *uid));
+    }
+    uid_names
+}
 
-        let mut total_free: u64 = 0;
-        for (j, thread2) in THREAD_STORE.iter().enumerate() {
-            let Some(name) = get_tid_name(j) else {
-                continue;
+impl Tracker {
+    /// Generate a memory usage report
+    /// Note that the numbers are not a synchronized snapshot, and have slight timing skew.
+    pub fn thread_report(&self) -> ThreadReport {
+        let threads = &self.threads;
+        ThreadReport(untracked(|| {
+            let exited_threads = threads.lock_exited_threads().clone();
+            let uid_names = thread_uid_names(threads, &exited_threads);
+            let get_uid_name = |uid: usize| {
+                uid_names
+                    .get(&uid)
+                    .map(|x| &**x)
+                    .unwrap_or(EXITED_THREADS_NAME)
             };
-            let freed = thread2.free[i].load(Ordering::Relaxed);
-            if freed == 0 {
-                continue;
+
+            let mut metrics: BTreeMap<String, ThreadMetric> = BTreeMap::new();
+            let add_counters = |metric: &mut ThreadMetric, name: &str, counters: ThreadCounters| {
+                metric.total_alloc += counters.alloc as u64;
+                metric.total_freed += counters.did_free as u64;
+                metric.realloc.add(&counters.realloc);
+                metric.failed_allocs += counters.failed_allocs as u64;
+                metric.failed_bytes += counters.failed_bytes as u64;
+                metric.live_allocations += counters.live.allocations as u64;
+                metric.peak.max(&counters.live.peak());
+                metric.lifetimes.add(&counters.lifetimes);
+                metric.sizes.add(&counters.sizes);
+                let mut total_freed = counters.self_freed as u64;
+                if counters.self_freed != 0 {
+                    *metric.freed_by_others.entry(name.to_string()).or_default() +=
+                        counters.self_freed as u64;
+                }
+                for (freed_by, freed) in counters.freed_by_others {
+                    total_freed += freed as u64;
+                    *metric
+                        .freed_by_others
+                        .entry(get_uid_name(freed_by).to_string())
+                        .or_default() += freed as u64;
+                }
+                metric.total_did_free += total_freed;
+                metric.current_used += (counters.alloc as u64).saturating_sub(total_freed);
+            };
+
+            for i in 0..threads.len() {
+                let Some(thread) = threads.get(i) else {
+                    continue;
+                };
+                let uid = if i == RETIRED_SLOT {
+                    RETIRED_UID
+                } else {
+                    thread.uid.load(Ordering::Acquire)
+                };
+                if uid == 0 {
+                    continue;
+                }
+                let counters = thread.counters();
+                if i == RETIRED_SLOT && counters.alloc == 0 && counters.did_free == 0 {
+                    continue;
+                }
+                let name = get_uid_name(uid);
+                add_counters(metrics.entry(name.to_string()).or_default(), name, counters);
             }
-            total_free += freed as u64;
-            *metric.freed_by_others.entry(name.into()).or_default() += freed as u64;
-        }
-        metric.total_did_free += total_free;
-        metric.total_freed += thread
-            .free
-            .iter()
-            .map(|x| x.load(Ordering::Relaxed) as u64)
-            .sum::<u64>();
-        metric.current_used += alloced.saturating_sub(total_free);
+
+            for (uid, thread) in exited_threads {
+                let name = get_uid_name(uid);
+                let metric = metrics.entry(name.to_string()).or_default();
+                metric.exited_at = Some(thread.exited_at);
+                add_counters(metric, name, thread.counters);
+            }
+            metrics
+        }))
     }
-    ThreadReport(metrics)
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "fs"))]
 mod tests {
     use super::*;
 
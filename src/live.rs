@@ -0,0 +1,145 @@
+use std::{collections::HashMap, time::Duration};
+
+#[cfg(feature = "backtrace")]
+use crate::HashedBacktrace;
+use crate::{
+    enter_alloc, now_nanos, thread_uid_names, Tracker, EXITED_THREADS_NAME, GLOBAL_TRACKER,
+};
+
+/// A single allocation that has not been freed yet, see `visit_live_allocations`
+#[derive(Debug, Clone)]
+pub struct LiveAllocation {
+    /// Address of the allocation
+    pub address: usize,
+    /// Size of the allocation in bytes
+    pub size: usize,
+    /// Alignment the allocation was requested with
+    pub align: usize,
+    /// Time since the allocation was made, as of when it was visited
+    pub age: Duration,
+    /// Id of the thread that owns the allocation, as in `AllocFailure::thread_id`.
+    /// This is the allocating thread, or the last thread to move it by reallocating.
+    pub thread_id: usize,
+    /// Name of the owning thread, as used in `thread_report`
+    pub thread_name: String,
+    /// Hash of the allocation's backtrace, if one was captured for it
+    #[cfg(feature = "backtrace")]
+    pub trace_hash: Option<u64>,
+    /// Tracker the allocation was visited in, to resolve its backtrace
+    #[cfg(feature = "backtrace")]
+    tracker: &'static Tracker,
+}
+
+impl LiveAllocation {
+    /// Look up and symbolize the backtrace of this allocation, if one was captured for it
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace(&self) -> Option<HashedBacktrace> {
+        self.tracker.resolve_backtrace(self.trace_hash?)
+    }
+}
+
+struct RawAllocation {
+    address: usize,
+    size: usize,
+    align: usize,
+    allocated_at: u64,
+    thread_id: usize,
+    #[cfg(feature = "backtrace")]
+    trace_hash: Option<u64>,
+}
+
+/// Call `visitor` for every allocation tracked by the global tracker that has not been freed yet,
+/// see `Tracker::visit_live_allocations`
+pub fn visit_live_allocations(visitor: impl FnMut(&LiveAllocation)) {
+    GLOBAL_TRACKER.visit_live_allocations(visitor);
+}
+
+/// Collect every allocation tracked by the global tracker that has not been freed yet, see `visit_live_allocations`
+pub fn live_allocations() -> Vec<LiveAllocation> {
+    GLOBAL_TRACKER.live_allocations()
+}
+
+impl Tracker {
+    /// Call `visitor` for every tracked allocation that has not been freed yet, in no particular order.
+    /// The set of allocations is copied up front, so `visitor` may allocate freely (allocations it makes are not visited),
+    /// and allocations freed concurrently may still be visited.
+    pub fn visit_live_allocations(&'static self, mut visitor: impl FnMut(&LiveAllocation)) {
+        // copied while in `IN_ALLOC`, as allocating while holding a pointer map shard would deadlock
+        let (allocations, uid_names) = enter_alloc(|| {
+            let allocations: Vec<RawAllocation> = self
+                .maps()
+                .ptr_map
+                .iter()
+                .map(|entry| RawAllocation {
+                    address: *entry.key(),
+                    size: entry.size,
+                    align: entry.align,
+                    allocated_at: entry.allocated_at,
+                    thread_id: entry.alloc_thread_uid,
+                    #[cfg(feature = "backtrace")]
+                    trace_hash: (entry.sample_weight > 0.0).then_some(entry.trace_hash),
+                })
+                .collect();
+            let uid_names: HashMap<usize, String> =
+                thread_uid_names(&self.threads, &self.threads.lock_exited_threads());
+            (allocations, uid_names)
+        });
+        for allocation in &allocations {
+            visitor(&LiveAllocation {
+                address: allocation.address,
+                size: allocation.size,
+                align: allocation.align,
+                age: Duration::from_nanos(now_nanos().saturating_sub(allocation.allocated_at)),
+                thread_id: allocation.thread_id,
+                thread_name: uid_names
+                    .get(&allocation.thread_id)
+                    .map(|x| &**x)
+                    .unwrap_or(EXITED_THREADS_NAME)
+                    .to_string(),
+                #[cfg(feature = "backtrace")]
+                trace_hash: allocation.trace_hash,
+                #[cfg(feature = "backtrace")]
+                tracker: self,
+            });
+        }
+        enter_alloc(|| drop((allocations, uid_names)));
+    }
+
+    /// Collect every tracked allocation that has not been freed yet, see `visit_live_allocations`
+    pub fn live_allocations(&'static self) -> Vec<LiveAllocation> {
+        let mut out = vec![];
+        self.visit_live_allocations(|allocation| out.push(allocation.clone()));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode};
+
+    #[test]
+    fn test_visit_live_allocations() {
+        let alloc = AllocTrack::new(System, BacktraceMode::None);
+        let layout = Layout::from_size_align(48, 16).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+        let find = |address: usize| {
+            let mut found = None;
+            visit_live_allocations(|allocation| {
+                if allocation.address == address {
+                    found = Some((allocation.size, allocation.align));
+                }
+            });
+            found
+        };
+        assert_eq!(find(ptr as usize), Some((48, 16)));
+
+        let ptr = unsafe { alloc.realloc(ptr, layout, 96) };
+        assert_eq!(find(ptr as usize), Some((96, 16)));
+
+        unsafe { alloc.dealloc(ptr, Layout::from_size_align(96, 16).unwrap()) };
+        assert_eq!(find(ptr as usize), None);
+    }
+}
@@ -0,0 +1,109 @@
+//! Offline analysis of recordings written by `alloc_track::start_recording`
+
+use std::{alloc::System, fs::File, io::BufReader, process::ExitCode};
+
+use alloc_track::{RecordingAnalysis, ReplayStats};
+
+const USAGE: &str = "\
+usage:
+    alloc-track report <recording> [--at <seconds>]
+        thread and backtrace reports at <seconds> since tracking started, or at the end of the recording
+    alloc-track leaks <recording>
+        allocations still live at the end of the recording
+    alloc-track peak <recording>
+        thread and backtrace reports at the moment of peak usage
+    alloc-track replay <recording> [<allocator>...]
+        replay the recording against each allocator, measuring wall time, peak RSS and fragmentation.
+        allocators: system, jemalloc (with the jemalloc feature), mimalloc (with the mimalloc feature),
+        all available by default. Replay one allocator per run for independent RSS numbers";
+
+const NANOS_PER_SECOND: f64 = 1_000_000_000.0;
+
+fn seconds(nanos: u64) -> f64 {
+    nanos as f64 / NANOS_PER_SECOND
+}
+
+/// Allocators available to `alloc-track replay`
+const ALLOCATORS: &[&str] = &[
+    "system",
+    #[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
+    "jemalloc",
+    #[cfg(feature = "mimalloc")]
+    "mimalloc",
+];
+
+/// Replay `analysis` against the allocator called `name`
+fn replay(analysis: &RecordingAnalysis, name: &str) -> Result<ReplayStats, String> {
+    Ok(match name {
+        "system" => analysis.replay(&System),
+        #[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
+        "jemalloc" => analysis.replay(&tikv_jemallocator::Jemalloc),
+        #[cfg(feature = "mimalloc")]
+        "mimalloc" => analysis.replay(&mimalloc::MiMalloc),
+        _ => {
+            return Err(format!(
+                "unknown allocator {name}, available: {}",
+                ALLOCATORS.join(", ")
+            ))
+        }
+    })
+}
+
+/// Print the reports of `analysis` at `at`
+fn print_reports(analysis: &RecordingAnalysis, at: u64) {
+    println!("AT {:.3}s\n{}", seconds(at), analysis.global_stats(at));
+    println!("THREADS\n{}", analysis.thread_report(at));
+    #[cfg(feature = "backtrace")]
+    println!("BACKTRACES\n{}", analysis.backtrace_report(at));
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (command, path, options) = match args {
+        [command, path, options @ ..] => (command.as_str(), path, options),
+        _ => return Err(USAGE.to_string()),
+    };
+    let file = File::open(path).map_err(|e| format!("failed to open {path}: {e}"))?;
+    let analysis = RecordingAnalysis::read(BufReader::new(file))
+        .map_err(|e| format!("failed to read {path}: {e}"))?;
+    match (command, options) {
+        ("report", []) => print_reports(&analysis, analysis.end_time()),
+        ("report", [flag, at]) if flag == "--at" => {
+            let at: f64 = at.parse().map_err(|_| format!("invalid time {at}"))?;
+            print_reports(&analysis, (at * NANOS_PER_SECOND) as u64);
+        }
+        ("leaks", []) => {
+            let end = analysis.end_time();
+            println!("AT {:.3}s\n{}", seconds(end), analysis.global_stats(end));
+            println!("THREADS\n{}", analysis.thread_report(end));
+            #[cfg(feature = "backtrace")]
+            println!("LEAKED BACKTRACES\n{}", analysis.leak_report());
+        }
+        ("peak", []) => {
+            let (at, peak) = analysis.peak();
+            println!("PEAK {peak}");
+            print_reports(&analysis, at);
+        }
+        ("replay", allocators) => {
+            let allocators: Vec<&str> = match allocators {
+                [] => ALLOCATORS.to_vec(),
+                allocators => allocators.iter().map(|x| x.as_str()).collect(),
+            };
+            for name in allocators {
+                println!("{}\n{}", name.to_uppercase(), replay(&analysis, name)?);
+            }
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
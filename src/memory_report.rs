@@ -0,0 +1,170 @@
+use std::{collections::BTreeMap, fmt, io};
+
+use procfs::process::{MMapPath, Process};
+
+use crate::{untracked, Size, ThreadReport, Tracker, GLOBAL_TRACKER};
+
+/// Memory usage of the process as seen by the kernel, next to what alloc-track saw of it.
+/// Comparing the two shows how much of the resident memory is allocator overhead, fragmentation,
+/// or memory not allocated through a tracked allocator at all, i.e. thread stacks, `mmap` or foreign libraries.
+#[derive(Clone)]
+pub struct ProcessMemoryReport {
+    /// Resident set size, `VmRSS` in `/proc/self/status`
+    pub rss: u64,
+    /// Peak resident set size, `VmHWM` in `/proc/self/status`
+    pub peak_rss: u64,
+    /// Resident anonymous memory, `RssAnon` in `/proc/self/status`
+    pub rss_anon: u64,
+    /// Resident file mappings, `RssFile` in `/proc/self/status`
+    pub rss_file: u64,
+    /// Resident shared memory, `RssShmem` in `/proc/self/status`
+    pub rss_shmem: u64,
+    /// Virtual size of the process, `size` in `/proc/self/statm`
+    pub virtual_size: u64,
+    /// Virtual size of data and stack segments, `data` in `/proc/self/statm`
+    pub data: u64,
+    /// Proportional set size, `Pss` in `/proc/self/smaps_rollup`
+    pub pss: u64,
+    /// Resident anonymous pages, `Anonymous` in `/proc/self/smaps_rollup`
+    pub anonymous: u64,
+    /// Pages written to and not shared, `Private_Dirty` in `/proc/self/smaps_rollup`
+    pub private_dirty: u64,
+    /// Anonymous memory swapped out, `Swap` in `/proc/self/smaps_rollup`
+    pub swap: u64,
+    /// Virtual size of the `[heap]` mapping grown by `brk`, from `/proc/self/maps`
+    pub heap_mapping: u64,
+    /// Number of anonymous mappings, where allocators place arenas and large allocations
+    pub anon_mappings: u64,
+    /// Virtual size of all anonymous mappings
+    pub anon_mapped: u64,
+    /// Live bytes of the tracker, as in `GlobalStats::live_bytes`
+    pub live_bytes: u64,
+    /// Thread report of the tracker, taken right after the process figures
+    pub threads: ThreadReport,
+}
+
+impl ProcessMemoryReport {
+    /// Resident set size per tracked live byte, `None` if nothing is live.
+    /// Close to 1.0 if nearly all resident memory is tracked allocations; overhead and untracked memory push it up.
+    pub fn rss_ratio(&self) -> Option<f64> {
+        (self.live_bytes != 0).then(|| self.rss as f64 / self.live_bytes as f64)
+    }
+}
+
+impl fmt::Display for ProcessMemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rss: {}", Size(self.rss))?;
+        writeln!(f, "peak_rss: {}", Size(self.peak_rss))?;
+        writeln!(f, "rss_anon: {}", Size(self.rss_anon))?;
+        writeln!(f, "rss_file: {}", Size(self.rss_file))?;
+        writeln!(f, "rss_shmem: {}", Size(self.rss_shmem))?;
+        writeln!(f, "virtual_size: {}", Size(self.virtual_size))?;
+        writeln!(f, "data: {}", Size(self.data))?;
+        writeln!(f, "pss: {}", Size(self.pss))?;
+        writeln!(f, "anonymous: {}", Size(self.anonymous))?;
+        writeln!(f, "private_dirty: {}", Size(self.private_dirty))?;
+        writeln!(f, "swap: {}", Size(self.swap))?;
+        writeln!(f, "heap_mapping: {}", Size(self.heap_mapping))?;
+        writeln!(
+            f,
+            "anon_mappings: {} ({})",
+            self.anon_mappings,
+            Size(self.anon_mapped)
+        )?;
+        writeln!(f, "live_bytes: {}", Size(self.live_bytes))?;
+        if let Some(ratio) = self.rss_ratio() {
+            writeln!(f, "rss_ratio: {ratio:.2}")?;
+        }
+        writeln!(f, "\nTHREADS\n{}", self.threads)
+    }
+}
+
+const KIB: u64 = 1024;
+
+/// Read the process figures of the report, leaving the tracker's fields empty
+fn read_proc_memory() -> procfs::ProcResult<ProcessMemoryReport> {
+    let process = Process::myself()?;
+    let status = process.status()?;
+    let statm = process.statm()?;
+    let rollup = process.smaps_rollup()?.memory_map_data.map;
+    let page_size = procfs::page_size()?;
+    let rollup_value = |key: &str| rollup.get(key).copied().unwrap_or(0);
+    let mut report = ProcessMemoryReport {
+        rss: status.vmrss.unwrap_or(0) * KIB,
+        peak_rss: status.vmhwm.unwrap_or(0) * KIB,
+        rss_anon: status.rssanon.unwrap_or(0) * KIB,
+        rss_file: status.rssfile.unwrap_or(0) * KIB,
+        rss_shmem: status.rssshmem.unwrap_or(0) * KIB,
+        virtual_size: statm.size * page_size,
+        data: statm.data * page_size,
+        pss: rollup_value("Pss"),
+        anonymous: rollup_value("Anonymous"),
+        private_dirty: rollup_value("Private_Dirty"),
+        swap: rollup_value("Swap"),
+        heap_mapping: 0,
+        anon_mappings: 0,
+        anon_mapped: 0,
+        live_bytes: 0,
+        threads: ThreadReport(BTreeMap::new()),
+    };
+    for map in process.maps()? {
+        let size = map.address.1 - map.address.0;
+        match map.pathname {
+            MMapPath::Heap => report.heap_mapping += size,
+            MMapPath::Anonymous => {
+                report.anon_mappings += 1;
+                report.anon_mapped += size;
+            }
+            _ => (),
+        }
+    }
+    Ok(report)
+}
+
+/// Report the memory usage of the process from `/proc/self` next to the global tracker, see `Tracker::process_memory_report`
+pub fn process_memory_report() -> io::Result<ProcessMemoryReport> {
+    GLOBAL_TRACKER.process_memory_report()
+}
+
+impl Tracker {
+    /// Report the memory usage of the process from `/proc/self/status`, `statm`, `smaps_rollup` and `maps`,
+    /// alongside the live bytes and thread report of this tracker.
+    /// The files are read without tracking, so the report does not count itself.
+    pub fn process_memory_report(&self) -> io::Result<ProcessMemoryReport> {
+        let mut report = untracked(|| read_proc_memory().map_err(|e| e.to_string()))
+            .map_err(io::Error::other)?;
+        report.live_bytes = self.global_stats().live_bytes;
+        report.threads = self.thread_report();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode};
+
+    #[test]
+    fn test_process_memory_report() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker);
+        let layout = Layout::from_size_align(1 << 20, 16).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+        unsafe { std::ptr::write_bytes(ptr, 1, layout.size()) };
+
+        let report = tracker.process_memory_report().unwrap();
+        assert_eq!(report.live_bytes, 1 << 20);
+        assert!(report.rss >= 1 << 20);
+        assert!(report.peak_rss >= report.rss);
+        assert!(report.anonymous > 0);
+        assert!(report.anon_mappings > 0);
+        assert!(report.rss_ratio().unwrap() >= 1.0);
+        assert!(!report.threads.0.is_empty());
+        assert!(report.to_string().contains("rss_ratio:"));
+
+        unsafe { alloc.dealloc(ptr, layout) };
+        assert_eq!(tracker.process_memory_report().unwrap().rss_ratio(), None);
+    }
+}
@@ -0,0 +1,132 @@
+use std::alloc::Layout;
+
+/// A tracked allocation, passed to `AllocObserver`
+#[derive(Debug, Clone, Copy)]
+pub struct AllocEvent {
+    pub ptr: *mut u8,
+    pub layout: Layout,
+    /// Slot of the calling thread, reused by later threads once it exits
+    pub thread_slot: usize,
+    /// alloc-track id of the calling thread, unique for the lifetime of the process
+    pub thread_id: usize,
+    /// Hash of the backtrace the allocation is accounted to, if its backtrace was captured.
+    /// Resolve it with `Tracker::resolve_backtrace`.
+    #[cfg(feature = "backtrace")]
+    pub trace_hash: Option<u64>,
+}
+
+/// Custom accounting hooked into `AllocTrack` or `TrackingAllocator` with `with_observer`, i.e. per-request counters.
+///
+/// Observers see every allocation that is tracked, after it is accounted, but not allocations made while paused or that failed.
+/// They are called inside the allocator with tracking suspended: they may allocate, but their own allocations are not tracked or observed.
+/// Those allocations may be freed later anywhere, i.e. by taking and dropping a `Vec` of recorded events on another thread.
+/// They must not panic, and must not call into reports of the tracker while holding locks they also take in a callback.
+pub trait AllocObserver: Sync {
+    /// Called after `event.ptr` was allocated
+    fn on_alloc(&self, event: &AllocEvent) {
+        let _ = event;
+    }
+
+    /// Called after `event.ptr` was freed. `event.layout` is the layout it was freed with.
+    fn on_dealloc(&self, event: &AllocEvent) {
+        let _ = event;
+    }
+
+    /// Called after the allocation at `old_ptr` with `old_layout` was moved or resized to `event.ptr` with `event.layout`
+    fn on_realloc(&self, old_ptr: *mut u8, old_layout: Layout, event: &AllocEvent) {
+        let _ = (old_ptr, old_layout, event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, System},
+        sync::Mutex,
+    };
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode, Tracker};
+
+    /// Records every event, allocating in the allocator
+    struct Recorder(Mutex<Vec<(&'static str, usize, usize)>>);
+
+    impl AllocObserver for Recorder {
+        fn on_alloc(&self, event: &AllocEvent) {
+            let mut events = self.0.lock().unwrap();
+            events.push(("alloc", event.ptr as usize, event.layout.size()));
+        }
+
+        fn on_dealloc(&self, event: &AllocEvent) {
+            let mut events = self.0.lock().unwrap();
+            events.push(("dealloc", event.ptr as usize, event.layout.size()));
+        }
+
+        fn on_realloc(&self, old_ptr: *mut u8, _old_layout: Layout, event: &AllocEvent) {
+            let mut events = self.0.lock().unwrap();
+            events.push(("realloc", old_ptr as usize, event.layout.size()));
+        }
+    }
+
+    static RECORDER: Recorder = Recorder(Mutex::new(Vec::new()));
+
+    #[test]
+    fn test_observer() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::None)
+            .with_tracker(tracker)
+            .with_observer(&RECORDER);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            let new_ptr = alloc.realloc(ptr, layout, 64);
+            alloc.dealloc(new_ptr, Layout::from_size_align(64, 8).unwrap());
+            assert_eq!(
+                *RECORDER.0.lock().unwrap(),
+                [
+                    ("alloc", ptr as usize, 32),
+                    ("realloc", ptr as usize, 64),
+                    ("dealloc", new_ptr as usize, 64),
+                ]
+            );
+        }
+    }
+
+    /// Allocates a block through the observed allocator for every 64 byte allocation, as an observer filling a `Vec` would
+    struct Collector(Mutex<Vec<usize>>);
+
+    const COLLECTED: Layout = Layout::new::<[u64; 2]>();
+
+    impl AllocObserver for Collector {
+        fn on_alloc(&self, event: &AllocEvent) {
+            if event.layout.size() == 64 {
+                let ptr = unsafe { COLLECTING_ALLOC.alloc(COLLECTED) };
+                self.0.lock().unwrap().push(ptr as usize);
+            }
+        }
+    }
+
+    static COLLECTOR: Collector = Collector(Mutex::new(Vec::new()));
+    static COLLECTING_TRACKER: Tracker = Tracker::new();
+    static COLLECTING_ALLOC: AllocTrack<System> = AllocTrack::new(System, BacktraceMode::None)
+        .with_tracker(&COLLECTING_TRACKER)
+        .with_observer(&COLLECTOR);
+
+    #[test]
+    fn test_observer_allocations_freed_outside() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let ptr = COLLECTING_ALLOC.alloc(layout);
+            COLLECTING_ALLOC.dealloc(ptr, layout);
+        }
+        // drained and freed outside the allocator, which must not be taken for a double free
+        let collected = std::mem::take(&mut *COLLECTOR.0.lock().unwrap());
+        assert_eq!(collected.len(), 1);
+        for ptr in collected {
+            unsafe { COLLECTING_ALLOC.dealloc(ptr as *mut u8, COLLECTED) };
+        }
+        let stats = COLLECTING_TRACKER.global_stats();
+        assert_eq!((stats.total_allocated, stats.total_freed), (64, 64));
+        assert_eq!(stats.live_allocations, 0);
+    }
+}
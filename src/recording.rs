@@ -0,0 +1,741 @@
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+#[cfg(feature = "backtrace")]
+use crate::backtrace_support::backtrace_string;
+use crate::{
+    current_thread_name, enter_alloc, thread_store::current_thread, BacktraceMode, Tracker,
+    Untracked, GLOBAL_TRACKER,
+};
+
+/// First bytes of a recording file
+const MAGIC: &[u8; 8] = b"ALLOCREC";
+/// Version of the recording format, written after `MAGIC`
+const VERSION: u64 = 2;
+/// Size at which a thread buffer is written to the file
+const FLUSH_THRESHOLD: usize = 64 * 1024;
+/// Longest encoding of an allocation, free or reallocation: the kind, up to 8 varints and a trace hash
+const MAX_EVENT_LEN: usize = 1 + 8 * 10 + 8;
+/// Size of a thread buffer, which always has room for one more event below `FLUSH_THRESHOLD`
+const BUFFER_CAPACITY: usize = FLUSH_THRESHOLD + MAX_EVENT_LEN;
+
+const RECORD_ALLOC: u8 = 1;
+const RECORD_FREE: u8 = 2;
+const RECORD_REALLOC: u8 = 3;
+const RECORD_THREAD: u8 = 4;
+const RECORD_BACKTRACE: u8 = 5;
+
+/// Ids of recordings, 0 is no recording
+static NEXT_RECORDING_ID: AtomicU64 = AtomicU64::new(1);
+
+/// An entry of a recording file, see `Tracker::start_recording`.
+/// Pointers, sizes and thread ids are widened to u64, times are nanoseconds since tracking started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Alloc {
+        time: u64,
+        ptr: u64,
+        size: u64,
+        align: u64,
+        thread_id: u64,
+        /// Hash of the backtrace the allocation is accounted to, described by a `Record::Backtrace`
+        trace_hash: Option<u64>,
+    },
+    Free {
+        time: u64,
+        ptr: u64,
+        size: u64,
+        thread_id: u64,
+    },
+    /// A reallocation takes effect in two steps: `old_ptr` is handed back to the allocator at `released_at`,
+    /// and may be reused by another thread from then on, while `ptr` is only returned at `time`.
+    Realloc {
+        time: u64,
+        released_at: u64,
+        old_ptr: u64,
+        ptr: u64,
+        old_size: u64,
+        size: u64,
+        align: u64,
+        thread_id: u64,
+        trace_hash: Option<u64>,
+    },
+    /// Name of a thread as in `thread_report`, before its first event
+    Thread { thread_id: u64, name: String },
+    /// Symbolized backtrace of a trace hash, written before the first batch of events referencing it
+    Backtrace {
+        hash: u64,
+        backtrace: String,
+        /// Mode the backtrace was written in
+        mode: BacktraceMode,
+    },
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_trace(out: &mut Vec<u8>, trace_hash: Option<u64>) {
+    out.extend_from_slice(&trace_hash.unwrap_or(0).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, value: &str) {
+    write_varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+impl Record {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Record::Alloc {
+                time,
+                ptr,
+                size,
+                align,
+                thread_id,
+                trace_hash,
+            } => {
+                out.push(RECORD_ALLOC);
+                for value in [*time, *ptr, *size, *align, *thread_id] {
+                    write_varint(out, value);
+                }
+                write_trace(out, *trace_hash);
+            }
+            Record::Free {
+                time,
+                ptr,
+                size,
+                thread_id,
+            } => {
+                out.push(RECORD_FREE);
+                for value in [*time, *ptr, *size, *thread_id] {
+                    write_varint(out, value);
+                }
+            }
+            Record::Realloc {
+                time,
+                released_at,
+                old_ptr,
+                ptr,
+                old_size,
+                size,
+                align,
+                thread_id,
+                trace_hash,
+            } => {
+                out.push(RECORD_REALLOC);
+                for value in [
+                    *time,
+                    *released_at,
+                    *old_ptr,
+                    *ptr,
+                    *old_size,
+                    *size,
+                    *align,
+                    *thread_id,
+                ] {
+                    write_varint(out, value);
+                }
+                write_trace(out, *trace_hash);
+            }
+            Record::Thread { thread_id, name } => {
+                out.push(RECORD_THREAD);
+                write_varint(out, *thread_id);
+                write_str(out, name);
+            }
+            Record::Backtrace {
+                hash,
+                backtrace,
+                mode,
+            } => {
+                out.push(RECORD_BACKTRACE);
+                out.extend_from_slice(&hash.to_le_bytes());
+                write_str(out, backtrace);
+                out.push(*mode as u8);
+            }
+        }
+    }
+
+    fn trace_hash(&self) -> Option<u64> {
+        match self {
+            Record::Alloc { trace_hash, .. } | Record::Realloc { trace_hash, .. } => *trace_hash,
+            _ => None,
+        }
+    }
+}
+
+/// Reads the records of a file written by `Tracker::start_recording`.
+///
+/// Threads write their events in batches, so records are only ordered by time within each thread.
+/// Sort them by `time` to replay the whole process, see `RecordingAnalysis`.
+/// Thread names and symbolized backtraces come before the first batch of events referencing them.
+pub struct RecordingReader<R> {
+    reader: R,
+}
+
+impl<R: Read> RecordingReader<R> {
+    /// Check the header of a recording, and read its records with `Iterator::next`
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an alloc-track recording",
+            ));
+        }
+        let mut out = Self { reader };
+        let version = out.read_varint()?;
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported recording version {version}"),
+            ));
+        }
+        Ok(out)
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.reader.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "varint too long",
+        ))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn read_trace(&mut self) -> io::Result<Option<u64>> {
+        Ok(Some(self.read_u64()?).filter(|hash| *hash != 0))
+    }
+
+    fn read_str(&mut self) -> io::Result<String> {
+        let len = self.read_varint()?;
+        let mut bytes = vec![];
+        (&mut self.reader).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn read_record(&mut self, kind: u8) -> io::Result<Record> {
+        Ok(match kind {
+            RECORD_ALLOC => Record::Alloc {
+                time: self.read_varint()?,
+                ptr: self.read_varint()?,
+                size: self.read_varint()?,
+                align: self.read_varint()?,
+                thread_id: self.read_varint()?,
+                trace_hash: self.read_trace()?,
+            },
+            RECORD_FREE => Record::Free {
+                time: self.read_varint()?,
+                ptr: self.read_varint()?,
+                size: self.read_varint()?,
+                thread_id: self.read_varint()?,
+            },
+            RECORD_REALLOC => Record::Realloc {
+                time: self.read_varint()?,
+                released_at: self.read_varint()?,
+                old_ptr: self.read_varint()?,
+                ptr: self.read_varint()?,
+                old_size: self.read_varint()?,
+                size: self.read_varint()?,
+                align: self.read_varint()?,
+                thread_id: self.read_varint()?,
+                trace_hash: self.read_trace()?,
+            },
+            RECORD_THREAD => Record::Thread {
+                thread_id: self.read_varint()?,
+                name: self.read_str()?,
+            },
+            RECORD_BACKTRACE => Record::Backtrace {
+                hash: self.read_u64()?,
+                backtrace: self.read_str()?,
+                // modes unknown without the `backtrace` feature are never reported
+                mode: BacktraceMode::from_u8(self.read_u8()?).unwrap_or_default(),
+            },
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown record kind {kind}"),
+                ))
+            }
+        })
+    }
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let kind = match self.read_u8() {
+            Ok(kind) => kind,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(self.read_record(kind))
+    }
+}
+
+/// Events of one thread not yet written to the file.
+/// Only the owning thread appends, without locking: it publishes each record by storing `len`.
+/// Published bytes are only read, and `len` only reset, with the file of the recording locked.
+struct ThreadBuffer {
+    bytes: Box<[AtomicU8]>,
+    len: AtomicUsize,
+}
+
+impl ThreadBuffer {
+    fn new() -> Self {
+        Self {
+            bytes: (0..BUFFER_CAPACITY).map(|_| AtomicU8::new(0)).collect(),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Append an encoded event. Must be called by the owning thread, with `len` below `FLUSH_THRESHOLD`.
+    fn append(&self, record: &[u8]) {
+        let len = self.len.load(Ordering::Relaxed);
+        for (byte, value) in self.bytes[len..len + record.len()].iter().zip(record) {
+            byte.store(*value, Ordering::Relaxed);
+        }
+        self.len.store(len + record.len(), Ordering::Release);
+    }
+
+    /// Copy the published bytes to `out`. Must be called with the file locked.
+    fn published(&self, out: &mut Vec<u8>) {
+        let len = self.len.load(Ordering::Acquire);
+        out.extend(
+            self.bytes[..len]
+                .iter()
+                .map(|byte| byte.load(Ordering::Relaxed)),
+        );
+    }
+}
+
+/// The file of an ongoing recording, with what was written to it
+struct RecordingFile {
+    writer: BufWriter<File>,
+    /// Trace hashes with a `Record::Backtrace` in the file
+    traces: Untracked<HashSet<u64>>,
+    /// Scratch space for batches being written
+    batch: Untracked<Vec<u8>>,
+    /// First error writing to `writer`, returned when the recording stops
+    error: Option<io::Error>,
+}
+
+impl RecordingFile {
+    fn write(&mut self, bytes: &[u8]) {
+        if self.error.is_none() {
+            if let Err(e) = self.writer.write_all(bytes) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Write the published events of `buffer`, preceded by the backtraces they reference that are not in the file yet.
+    /// Flushed right away, so that a process dying mid-recording leaves whole batches behind. Must be called with `IN_ALLOC` set.
+    fn write_buffer(&mut self, tracker: &'static Tracker, buffer: &ThreadBuffer) {
+        let mut batch = std::mem::take(&mut *self.batch);
+        batch.clear();
+        buffer.published(&mut batch);
+        if batch.is_empty() {
+            *self.batch = batch;
+            return;
+        }
+        let events = RecordingReader { reader: &batch[..] };
+        let mut backtraces = vec![];
+        for record in events.flatten() {
+            let Some(hash) = record.trace_hash() else {
+                continue;
+            };
+            if self.traces.insert(hash) {
+                if let Some(backtrace) = tracker.encode_backtrace(hash) {
+                    backtrace.encode(&mut backtraces);
+                }
+            }
+        }
+        self.write(&backtraces);
+        self.write(&batch);
+        if self.error.is_none() {
+            if let Err(e) = self.writer.flush() {
+                self.error = Some(e);
+            }
+        }
+        *self.batch = batch;
+    }
+}
+
+/// An ongoing recording of a tracker
+pub(crate) struct Recording {
+    id: u64,
+    tracker: &'static Tracker,
+    /// Taken when the recording stops
+    file: Mutex<Option<RecordingFile>>,
+    /// Buffers of all threads that recorded an event and have not exited
+    buffers: Mutex<Vec<Arc<ThreadBuffer>>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poison| poison.into_inner())
+}
+
+/// The buffer of the current thread for one recording
+struct LocalBuffer {
+    recording: Arc<Recording>,
+    buffer: Arc<ThreadBuffer>,
+    /// Encoding of the event being recorded
+    scratch: Vec<u8>,
+}
+
+impl LocalBuffer {
+    /// Register a buffer for the current thread, writing its name to the file. Must be called with `IN_ALLOC` set.
+    fn new(recording: Arc<Recording>) -> Self {
+        let thread = current_thread();
+        let mut scratch = vec![];
+        Record::Thread {
+            thread_id: thread.uid as u64,
+            name: current_thread_name().unwrap_or_else(|| thread.uid.to_string()),
+        }
+        .encode(&mut scratch);
+        if let Some(file) = &mut *lock(&recording.file) {
+            file.write(&scratch);
+        }
+        let buffer = Arc::new(ThreadBuffer::new());
+        lock(&recording.buffers).push(buffer.clone());
+        Self {
+            recording,
+            buffer,
+            scratch,
+        }
+    }
+
+    fn push(&mut self, record: &Record) {
+        self.scratch.clear();
+        record.encode(&mut self.scratch);
+        self.buffer.append(&self.scratch);
+        if self.buffer.len.load(Ordering::Relaxed) >= FLUSH_THRESHOLD {
+            self.flush();
+        }
+    }
+
+    /// Write out and empty the buffer
+    fn flush(&self) {
+        let mut file = lock(&self.recording.file);
+        if let Some(file) = &mut *file {
+            file.write_buffer(self.recording.tracker, &self.buffer);
+        }
+        self.buffer.len.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Drop for LocalBuffer {
+    fn drop(&mut self) {
+        enter_alloc(|| {
+            self.flush();
+            lock(&self.recording.buffers).retain(|buffer| !Arc::ptr_eq(buffer, &self.buffer));
+        });
+    }
+}
+
+thread_local! {
+    /// Buffers of the current thread, one per active recording it has events in
+    static LOCAL_BUFFERS: Untracked<RefCell<Vec<LocalBuffer>>> =
+        const { Untracked::new(RefCell::new(Vec::new())) };
+}
+
+/// Record the events of the global tracker to `path`, see `Tracker::start_recording`
+pub fn start_recording(path: impl AsRef<Path>) -> io::Result<()> {
+    GLOBAL_TRACKER.start_recording(path)
+}
+
+/// Stop recording the events of the global tracker, see `Tracker::stop_recording`
+pub fn stop_recording() -> io::Result<()> {
+    GLOBAL_TRACKER.stop_recording()
+}
+
+impl Tracker {
+    /// Stream every tracked allocation, free and reallocation to a compact binary file at `path`, to analyse the full sequence of events offline.
+    /// Read it back with `RecordingReader`.
+    ///
+    /// Events are appended without locking to a buffer owned by the allocating thread, and written to the file in batches of 64 KiB,
+    /// so the allocation path only takes the lock of the file once per batch.
+    /// Each thread's name is written before its first batch, and each backtrace is symbolized and written before the first batch referencing it,
+    /// so that a recording cut short by a crash can still be analysed up to its last batch. Symbolizing a new backtrace delays the batch that references it.
+    /// Fails if the file can't be created, or this tracker is already recording.
+    pub fn start_recording(&'static self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        let mut header = vec![];
+        write_varint(&mut header, VERSION);
+        writer.write_all(&header)?;
+        writer.flush()?;
+        let mut file = Some(RecordingFile {
+            writer,
+            traces: Untracked::new(HashSet::new()),
+            batch: Untracked::new(Vec::new()),
+            error: None,
+        });
+        let started = enter_alloc(|| {
+            let mut recording = lock(&self.recording);
+            if recording.is_some() {
+                return false;
+            }
+            let id = NEXT_RECORDING_ID.fetch_add(1, Ordering::Relaxed);
+            *recording = Some(Arc::new(Recording {
+                id,
+                tracker: self,
+                file: Mutex::new(file.take()),
+                buffers: Mutex::new(vec![]),
+            }));
+            self.recording_id.store(id, Ordering::Release);
+            true
+        });
+        if !started {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "already recording",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Stop the recording started with `start_recording`, writing out the events buffered by all threads and the backtraces they reference.
+    /// Events of allocations racing with this call may be lost. Returns the first error writing the file, if any.
+    pub fn stop_recording(&'static self) -> io::Result<()> {
+        let Some(recording) = enter_alloc(|| {
+            self.recording_id.store(0, Ordering::Release);
+            lock(&self.recording).take()
+        }) else {
+            return Ok(());
+        };
+        let file = enter_alloc(|| {
+            let file = {
+                let mut file = lock(&recording.file);
+                if let Some(file) = &mut *file {
+                    for buffer in lock(&recording.buffers).iter() {
+                        file.write_buffer(self, buffer);
+                    }
+                }
+                file.take()
+            };
+            drop(recording);
+            file
+        });
+        // the file was created outside of the allocator, and is closed outside of it
+        let Some(RecordingFile {
+            mut writer, error, ..
+        }) = file
+        else {
+            return Ok(());
+        };
+        if let Some(e) = error {
+            return Err(e);
+        }
+        writer.flush()
+    }
+
+    /// Symbolize the backtrace of `hash` as a `Record::Backtrace`. Must be called with `IN_ALLOC` set.
+    #[cfg(feature = "backtrace")]
+    fn encode_backtrace(&'static self, hash: u64) -> Option<Record> {
+        let (Some(backtrace), Some(mode)) = (self.resolve_backtrace(hash), self.trace_mode(hash))
+        else {
+            return None;
+        };
+        Some(Record::Backtrace {
+            hash,
+            backtrace: backtrace_string(&backtrace, mode),
+            mode,
+        })
+    }
+
+    #[cfg(not(feature = "backtrace"))]
+    fn encode_backtrace(&'static self, _hash: u64) -> Option<Record> {
+        None
+    }
+
+    /// Append `record` to the buffer of the current thread, if recording. Must be called with `IN_ALLOC` set.
+    pub(crate) fn record(&'static self, record: Record) {
+        let id = self.recording_id.load(Ordering::Acquire);
+        if id == 0 {
+            return;
+        }
+        LOCAL_BUFFERS
+            .try_with(|buffers| {
+                let mut buffers = buffers.borrow_mut();
+                let index = match buffers.iter().position(|local| local.recording.id == id) {
+                    Some(index) => index,
+                    None => {
+                        // buffers of stopped recordings were written out when they stopped
+                        buffers.retain(|local| lock(&local.recording.file).is_some());
+                        let Some(recording) = lock(&self.recording).clone() else {
+                            return;
+                        };
+                        if recording.id != id {
+                            return;
+                        }
+                        buffers.push(LocalBuffer::new(recording));
+                        buffers.len() - 1
+                    }
+                };
+                buffers[index].push(&record);
+            })
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        fs,
+    };
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode};
+
+    #[test]
+    fn test_recording() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        #[cfg(feature = "backtrace")]
+        let mode = BacktraceMode::Short;
+        #[cfg(not(feature = "backtrace"))]
+        let mode = BacktraceMode::None;
+        let alloc = AllocTrack::new(System, mode).with_tracker(tracker);
+        let path = std::env::temp_dir().join(format!("alloc-track-{}.rec", std::process::id()));
+        tracker.start_recording(&path).unwrap();
+        assert!(tracker.start_recording(&path).is_err());
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let (ptr, new_ptr) = unsafe {
+            let ptr = alloc.alloc(layout);
+            let new_ptr = alloc.realloc(ptr, layout, 64);
+            alloc.dealloc(new_ptr, Layout::from_size_align(64, 8).unwrap());
+            (ptr, new_ptr)
+        };
+        tracker.stop_recording().unwrap();
+
+        let records = RecordingReader::new(fs::File::open(&path).unwrap())
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        let thread_id = current_thread().uid as u64;
+        assert!(matches!(&records[0], Record::Thread { thread_id: id, .. } if *id == thread_id));
+        // every backtrace is written before the first event referencing it
+        let mut traces = HashSet::new();
+        let mut events = vec![];
+        for record in &records[1..] {
+            match record {
+                Record::Backtrace {
+                    hash,
+                    mode: written,
+                    ..
+                } => {
+                    assert_eq!(*written, mode);
+                    assert!(traces.insert(*hash));
+                }
+                Record::Alloc { trace_hash, .. } | Record::Realloc { trace_hash, .. } => {
+                    assert!(trace_hash.is_none_or(|hash| traces.contains(&hash)));
+                    events.push(record);
+                }
+                _ => events.push(record),
+            }
+        }
+        let Record::Alloc {
+            ptr: alloc_ptr,
+            size: 32,
+            align: 8,
+            trace_hash,
+            ..
+        } = *events[0]
+        else {
+            panic!("expected an allocation, got {:?}", events[0]);
+        };
+        assert_eq!(alloc_ptr, ptr as u64);
+        assert!(
+            matches!(*events[1], Record::Realloc { time, released_at, old_ptr, ptr, size: 64, .. } if released_at <= time && old_ptr == alloc_ptr && ptr == new_ptr as u64)
+        );
+        assert!(matches!(*events[2], Record::Free { ptr, size: 64, .. } if ptr == new_ptr as u64));
+        assert_eq!(events.len(), 3);
+        assert_eq!(trace_hash.is_some(), cfg!(feature = "backtrace"));
+    }
+
+    #[test]
+    fn test_recording_threads() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc: &'static AllocTrack<System> = Box::leak(Box::new(
+            AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker),
+        ));
+        let path =
+            std::env::temp_dir().join(format!("alloc-track-threads-{}.rec", std::process::id()));
+        tracker.start_recording(&path).unwrap();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        // enough events to flush the buffers of some threads while recording, the others are written at stop
+        let threads = (0..4)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    for _ in 0..i * 2000 {
+                        unsafe { alloc.dealloc(alloc.alloc(layout), layout) };
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let ptr = unsafe { alloc.alloc(layout) };
+        tracker.stop_recording().unwrap();
+        unsafe { alloc.dealloc(ptr, layout) };
+
+        let records = RecordingReader::new(fs::File::open(&path).unwrap())
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut threads = HashSet::new();
+        let mut events = 0;
+        for record in &records {
+            match record {
+                Record::Thread { thread_id, .. } => assert!(threads.insert(*thread_id)),
+                Record::Alloc { thread_id, .. } | Record::Free { thread_id, .. } => {
+                    assert!(threads.contains(thread_id));
+                    events += 1;
+                }
+                _ => unreachable!("unexpected record {record:?}"),
+            }
+        }
+        assert_eq!(events, 2 * (2000 + 4000 + 6000) + 1);
+    }
+}
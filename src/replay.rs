@@ -0,0 +1,374 @@
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    collections::HashMap,
+    fmt,
+    ptr::{self, NonNull},
+    time::{Duration, Instant},
+};
+
+use crate::{analysis::Event, RecordingAnalysis, Size};
+
+/// Number of events between two samples of the resident set size
+const RSS_SAMPLE_INTERVAL: u64 = 4096;
+
+/// Resident set size of the process in bytes
+#[cfg(all(target_os = "linux", feature = "fs"))]
+fn current_rss() -> Option<u64> {
+    let statm = procfs::process::Process::myself().ok()?.statm().ok()?;
+    Some(statm.resident * procfs::page_size().ok()?)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "fs")))]
+fn current_rss() -> Option<u64> {
+    None
+}
+
+/// Results of replaying a recording against an allocator, see `RecordingAnalysis::replay`
+#[derive(Debug, Clone, Default)]
+pub struct ReplayStats {
+    /// Number of allocations, frees and reallocations replayed
+    pub events: u64,
+    /// Time spent replaying, including writing to every allocated byte
+    pub wall_time: Duration,
+    /// Most bytes requested and not freed at once
+    pub peak_live_bytes: u64,
+    /// Bytes requested and not freed at the end of the recording
+    pub final_live_bytes: u64,
+    /// Number of allocations and reallocations the allocator failed to satisfy
+    pub failed_allocs: u64,
+    /// Resident set size of the process before the replay. RSS is only measured on Linux with the `fs` feature.
+    pub baseline_rss: Option<u64>,
+    /// Highest resident set size sampled during the replay
+    pub peak_rss: Option<u64>,
+    /// Resident set size at the end of the recording, before the remaining allocations are freed
+    pub final_rss: Option<u64>,
+}
+
+impl ReplayStats {
+    /// Resident memory the replay grew the process by at its peak, per byte live at the peak of the replay.
+    /// 1.0 if the allocator had no overhead, values well above it mean freed memory was not reused, i.e. fragmentation.
+    ///
+    /// This is an estimate: RSS is only sampled every few thousand events and may miss its true peak,
+    /// the peaks of RSS and live bytes need not coincide, and memory the process had resident before the replay
+    /// and reused for it is not counted.
+    pub fn fragmentation(&self) -> Option<f64> {
+        let grown = self.peak_rss?.saturating_sub(self.baseline_rss?);
+        (self.peak_live_bytes != 0).then(|| grown as f64 / self.peak_live_bytes as f64)
+    }
+}
+
+impl fmt::Display for ReplayStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "events: {}", self.events)?;
+        writeln!(f, "wall_time: {:?}", self.wall_time)?;
+        writeln!(f, "peak_live: {}", Size(self.peak_live_bytes))?;
+        writeln!(f, "final_live: {}", Size(self.final_live_bytes))?;
+        if self.failed_allocs != 0 {
+            writeln!(f, "failed_allocs: {}", self.failed_allocs)?;
+        }
+        if let (Some(baseline), Some(peak), Some(last)) =
+            (self.baseline_rss, self.peak_rss, self.final_rss)
+        {
+            writeln!(f, "baseline_rss: {}", Size(baseline))?;
+            writeln!(f, "peak_rss: {}", Size(peak))?;
+            writeln!(f, "final_rss: {}", Size(last))?;
+        }
+        if let Some(fragmentation) = self.fragmentation() {
+            writeln!(f, "fragmentation: {fragmentation:.2}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Layout of a recorded allocation, `None` if it can't be passed to `GlobalAlloc`
+fn layout(size: u64, align: u64) -> Option<Layout> {
+    Layout::from_size_align(size as usize, align as usize)
+        .ok()
+        .filter(|layout| layout.size() != 0)
+}
+
+/// Allocate `layout` with `allocator`, writing to every byte as the recorded process presumably did
+unsafe fn replay_alloc<A: GlobalAlloc>(allocator: &A, layout: Layout) -> Option<NonNull<u8>> {
+    let ptr = NonNull::new(allocator.alloc(layout))?;
+    ptr::write_bytes(ptr.as_ptr(), 0xa5, layout.size());
+    Some(ptr)
+}
+
+/// Move `block` from `layout` to `new_layout` with `allocator`, returning null on failure.
+/// `GlobalAlloc::realloc` can't change the alignment, which `TrackingAllocator` allows.
+unsafe fn replay_realloc<A: GlobalAlloc>(
+    allocator: &A,
+    block: NonNull<u8>,
+    layout: Layout,
+    new_layout: Layout,
+) -> *mut u8 {
+    if layout.align() == new_layout.align() {
+        return allocator.realloc(block.as_ptr(), layout, new_layout.size());
+    }
+    let new_ptr = allocator.alloc(new_layout);
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(
+            block.as_ptr(),
+            new_ptr,
+            layout.size().min(new_layout.size()),
+        );
+        allocator.dealloc(block.as_ptr(), layout);
+    }
+    new_ptr
+}
+
+/// Keep `block` as the replayed allocation at the recorded address `ptr`.
+/// A block still kept at that address was freed without its free being recorded, and is freed now rather than leaked.
+unsafe fn insert_block<A: GlobalAlloc>(
+    allocator: &A,
+    blocks: &mut HashMap<u64, (NonNull<u8>, Layout)>,
+    live_bytes: &mut u64,
+    ptr: u64,
+    block: NonNull<u8>,
+    layout: Layout,
+) {
+    *live_bytes += layout.size() as u64;
+    if let Some((stale, stale_layout)) = blocks.insert(ptr, (block, layout)) {
+        allocator.dealloc(stale.as_ptr(), stale_layout);
+        *live_bytes -= stale_layout.size() as u64;
+    }
+}
+
+impl RecordingAnalysis {
+    /// Replay the allocations, frees and reallocations of the recording against `allocator`, to compare allocators on a real workload.
+    ///
+    /// Events are replayed as fast as possible on the calling thread, in the order they were recorded across all threads.
+    /// Allocations made before the recording started are not replayed, and frees of them are skipped.
+    /// All allocations still live at the end are freed before returning.
+    pub fn replay<A: GlobalAlloc>(&self, allocator: &A) -> ReplayStats {
+        let mut stats = ReplayStats {
+            baseline_rss: current_rss(),
+            ..Default::default()
+        };
+        stats.peak_rss = stats.baseline_rss;
+        let mut blocks: HashMap<u64, (NonNull<u8>, Layout)> = HashMap::new();
+        // blocks being reallocated, by reallocating thread
+        let mut moving: HashMap<u64, (NonNull<u8>, Layout)> = HashMap::new();
+        let mut live_bytes = 0u64;
+        let sample_rss = |stats: &mut ReplayStats| {
+            stats.peak_rss = stats.peak_rss.max(current_rss());
+        };
+        let start = Instant::now();
+        for event in self.events() {
+            match *event {
+                Event::Alloc {
+                    ptr, size, align, ..
+                } => {
+                    let Some(layout) = layout(size, align) else {
+                        continue;
+                    };
+                    match unsafe { replay_alloc(allocator, layout) } {
+                        Some(new_ptr) => unsafe {
+                            insert_block(
+                                allocator,
+                                &mut blocks,
+                                &mut live_bytes,
+                                ptr,
+                                new_ptr,
+                                layout,
+                            )
+                        },
+                        None => stats.failed_allocs += 1,
+                    }
+                }
+                Event::Free { ptr, .. } => {
+                    let Some((old_ptr, layout)) = blocks.remove(&ptr) else {
+                        continue;
+                    };
+                    unsafe { allocator.dealloc(old_ptr.as_ptr(), layout) };
+                    live_bytes -= layout.size() as u64;
+                }
+                Event::ReallocStart {
+                    old_ptr, thread_id, ..
+                } => {
+                    // the old address may be reused by another thread before the reallocation returns
+                    if let Some(block) = blocks.remove(&old_ptr) {
+                        moving.insert(thread_id, block);
+                    }
+                    continue;
+                }
+                Event::ReallocEnd {
+                    ptr,
+                    size,
+                    align,
+                    thread_id,
+                    ..
+                } => match (moving.remove(&thread_id), layout(size, align)) {
+                    (Some((block, layout)), Some(new_layout)) => {
+                        live_bytes -= layout.size() as u64;
+                        let new_ptr =
+                            unsafe { replay_realloc(allocator, block, layout, new_layout) };
+                        let (block, layout) = match NonNull::new(new_ptr) {
+                            Some(new_ptr) => {
+                                if size as usize > layout.size() {
+                                    unsafe {
+                                        ptr::write_bytes(
+                                            new_ptr.as_ptr().add(layout.size()),
+                                            0xa5,
+                                            size as usize - layout.size(),
+                                        )
+                                    };
+                                }
+                                (new_ptr, new_layout)
+                            }
+                            None => {
+                                // the original allocation is left untouched
+                                stats.failed_allocs += 1;
+                                (block, layout)
+                            }
+                        };
+                        unsafe {
+                            insert_block(
+                                allocator,
+                                &mut blocks,
+                                &mut live_bytes,
+                                ptr,
+                                block,
+                                layout,
+                            )
+                        };
+                    }
+                    (Some((block, layout)), None) => {
+                        // reallocated to nothing, so the old block is gone
+                        unsafe { allocator.dealloc(block.as_ptr(), layout) };
+                        live_bytes -= layout.size() as u64;
+                    }
+                    (None, Some(new_layout)) => {
+                        // allocated before the recording started
+                        match unsafe { replay_alloc(allocator, new_layout) } {
+                            Some(new_ptr) => unsafe {
+                                insert_block(
+                                    allocator,
+                                    &mut blocks,
+                                    &mut live_bytes,
+                                    ptr,
+                                    new_ptr,
+                                    new_layout,
+                                )
+                            },
+                            None => stats.failed_allocs += 1,
+                        }
+                    }
+                    (None, None) => continue,
+                },
+            }
+            stats.events += 1;
+            if live_bytes > stats.peak_live_bytes {
+                stats.peak_live_bytes = live_bytes;
+            }
+            // `is_multiple_of` needs Rust 1.87
+            #[allow(clippy::manual_is_multiple_of)]
+            if stats.events % RSS_SAMPLE_INTERVAL == 0 {
+                sample_rss(&mut stats);
+            }
+        }
+        stats.wall_time = start.elapsed();
+        stats.final_live_bytes = live_bytes;
+        stats.final_rss = current_rss();
+        sample_rss(&mut stats);
+        for (ptr, layout) in blocks.into_values().chain(moving.into_values()) {
+            unsafe { allocator.dealloc(ptr.as_ptr(), layout) };
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::System;
+
+    use super::*;
+    use crate::Record;
+
+    #[test]
+    fn test_replay() {
+        let alloc = |time, ptr, size| Record::Alloc {
+            time,
+            ptr,
+            size,
+            align: 16,
+            thread_id: 1,
+            trace_hash: None,
+        };
+        let analysis = RecordingAnalysis::from_records([
+            alloc(1, 0x1000, 4096),
+            alloc(2, 0x2000, 100),
+            Record::Realloc {
+                time: 3,
+                released_at: 3,
+                old_ptr: 0x2000,
+                ptr: 0x3000,
+                old_size: 100,
+                size: 200,
+                align: 16,
+                thread_id: 1,
+                trace_hash: None,
+            },
+            Record::Free {
+                time: 4,
+                ptr: 0x1000,
+                size: 4096,
+                thread_id: 1,
+            },
+            alloc(5, 0x5000, 300),
+            Record::Realloc {
+                time: 6,
+                released_at: 6,
+                old_ptr: 0x5000,
+                ptr: 0x6000,
+                old_size: 300,
+                size: 0,
+                align: 16,
+                thread_id: 1,
+                trace_hash: None,
+            },
+            // allocated before the recording started
+            Record::Free {
+                time: 7,
+                ptr: 0x4000,
+                size: 10,
+                thread_id: 1,
+            },
+            // reallocated from before the recording started
+            Record::Realloc {
+                time: 8,
+                released_at: 8,
+                old_ptr: 0x7000,
+                ptr: 0x8000,
+                old_size: 10,
+                size: 50,
+                align: 16,
+                thread_id: 1,
+                trace_hash: None,
+            },
+            Record::Realloc {
+                time: 9,
+                released_at: 9,
+                old_ptr: 0x9000,
+                ptr: 0xa000,
+                old_size: 10,
+                size: 1 << 60,
+                align: 16,
+                thread_id: 1,
+                trace_hash: None,
+            },
+            // the free of 0x3000 was not recorded
+            alloc(10, 0x3000, 20),
+        ]);
+        let stats = analysis.replay(&System);
+        assert_eq!(stats.events, 9);
+        assert_eq!(stats.peak_live_bytes, 4296);
+        assert_eq!(stats.final_live_bytes, 70);
+        assert_eq!(stats.failed_allocs, 1);
+        #[cfg(all(target_os = "linux", feature = "fs"))]
+        {
+            assert!(stats.peak_rss >= stats.baseline_rss && stats.baseline_rss.is_some());
+            assert!(stats.fragmentation().is_some());
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "backtrace")]
+use crate::{backtrace_support::fmt_backtrace, BacktraceMode, HashedBacktrace};
+use crate::{
+    enter_alloc, now_nanos, thread_store::current_thread, untracked, Size, Tracker, GLOBAL_TRACKER,
+};
+
+/// Allocations still live from a single backtrace in a `ScopeReport`
+#[cfg(feature = "backtrace")]
+#[derive(Debug, Clone)]
+pub struct ScopeMetric {
+    /// Bytes allocated here in the scope that are not freed
+    pub live_bytes: u64,
+    /// Number of allocations made here in the scope that are not freed
+    pub live_allocations: u64,
+    /// `mode` as copied from `AllocTrack`
+    pub mode: BacktraceMode,
+}
+
+#[cfg(feature = "backtrace")]
+impl fmt::Display for ScopeMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "live_bytes: {}", Size(self.live_bytes))?;
+        writeln!(f, "live_allocations: {}", self.live_allocations)?;
+        Ok(())
+    }
+}
+
+/// Allocations made within `track_scope` or `track_thread_scope` that were still live when the scope ended.
+/// Unlike `backtrace_report`, these are exact counts even when sampling, as they come from the tracked pointers themselves.
+#[derive(Clone)]
+pub struct ScopeReport {
+    /// How long the scope ran
+    pub elapsed: Duration,
+    /// Bytes allocated in the scope that are not freed
+    pub live_bytes: u64,
+    /// Number of allocations made in the scope that are not freed
+    pub live_allocations: u64,
+    /// Bytes of live allocations without a backtrace, i.e. with `BacktraceMode::None` or not sampled
+    #[cfg(feature = "backtrace")]
+    pub untraced_bytes: u64,
+    /// Number of live allocations without a backtrace
+    #[cfg(feature = "backtrace")]
+    pub untraced_allocations: u64,
+    /// Live allocations by backtrace, sorted by bytes
+    #[cfg(feature = "backtrace")]
+    pub backtraces: Vec<(HashedBacktrace, ScopeMetric)>,
+}
+
+impl ScopeReport {
+    /// Whether all allocations made in the scope were freed
+    pub fn is_empty(&self) -> bool {
+        self.live_allocations == 0
+    }
+}
+
+impl fmt::Display for ScopeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "elapsed: {:.03}s", self.elapsed.as_secs_f64())?;
+        writeln!(f, "live_bytes: {}", Size(self.live_bytes))?;
+        writeln!(f, "live_allocations: {}", self.live_allocations)?;
+        #[cfg(feature = "backtrace")]
+        {
+            if self.untraced_allocations != 0 {
+                writeln!(
+                    f,
+                    "untraced: {} ({} allocations)",
+                    Size(self.untraced_bytes),
+                    self.untraced_allocations
+                )?;
+            }
+            writeln!(f)?;
+            for (backtrace, metric) in &self.backtraces {
+                fmt_backtrace(f, backtrace, metric.mode)?;
+                writeln!(f, "\n{metric}\n\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run `func`, and report the allocations of the global tracker made while it ran that are still live, see `Tracker::track_scope`
+pub fn track_scope<T>(func: impl FnOnce() -> T) -> (T, ScopeReport) {
+    GLOBAL_TRACKER.track_scope(func)
+}
+
+/// Like `track_scope`, but only reports allocations made by the current thread, see `Tracker::track_thread_scope`
+pub fn track_thread_scope<T>(func: impl FnOnce() -> T) -> (T, ScopeReport) {
+    GLOBAL_TRACKER.track_thread_scope(func)
+}
+
+impl Tracker {
+    /// Run `func`, and report all allocations made while it ran, on any thread, that are still live when it returns.
+    /// Allocations are attributed by when they were made, so allocations made concurrently by unrelated threads are included.
+    /// Use `track_thread_scope` to only consider the current thread.
+    pub fn track_scope<T>(&'static self, func: impl FnOnce() -> T) -> (T, ScopeReport) {
+        self.run_scope(func, false)
+    }
+
+    /// Like `track_scope`, but only reports allocations made by the current thread.
+    /// Allocations made in the scope that another thread since reallocated are not included, as they changed ownership.
+    pub fn track_thread_scope<T>(&'static self, func: impl FnOnce() -> T) -> (T, ScopeReport) {
+        self.run_scope(func, true)
+    }
+
+    fn run_scope<T>(
+        &'static self,
+        func: impl FnOnce() -> T,
+        current_thread_only: bool,
+    ) -> (T, ScopeReport) {
+        let started = Instant::now();
+        let start = now_nanos();
+        let out = func();
+        let end = now_nanos();
+        let elapsed = started.elapsed();
+        let thread_uid = current_thread_only.then(|| enter_alloc(|| current_thread().uid));
+        (out, self.scope_report(start..=end, elapsed, thread_uid))
+    }
+
+    /// Report the live allocations made within `period`, by the thread `thread_uid` if given
+    #[cfg_attr(not(feature = "backtrace"), allow(unused_variables))]
+    fn scope_report(
+        &'static self,
+        period: RangeInclusive<u64>,
+        elapsed: Duration,
+        thread_uid: Option<usize>,
+    ) -> ScopeReport {
+        let mut report = ScopeReport {
+            elapsed,
+            live_bytes: 0,
+            live_allocations: 0,
+            #[cfg(feature = "backtrace")]
+            untraced_bytes: 0,
+            #[cfg(feature = "backtrace")]
+            untraced_allocations: 0,
+            #[cfg(feature = "backtrace")]
+            backtraces: vec![],
+        };
+        // (bytes, allocations) by trace hash, `None` for allocations without a backtrace
+        let by_trace: Vec<(Option<u64>, u64, u64)> = untracked(|| {
+            let mut by_trace: HashMap<Option<u64>, (u64, u64)> = HashMap::new();
+            for entry in self.maps().ptr_map.iter() {
+                // other threads keep allocating after the scope ended
+                if !period.contains(&entry.allocated_at)
+                    || thread_uid.is_some_and(|uid| entry.alloc_thread_uid != uid)
+                {
+                    continue;
+                }
+                #[cfg(feature = "backtrace")]
+                let hash = (entry.sample_weight > 0.0).then_some(entry.trace_hash);
+                #[cfg(not(feature = "backtrace"))]
+                let hash = None;
+                let counts = by_trace.entry(hash).or_default();
+                counts.0 += entry.size as u64;
+                counts.1 += 1;
+            }
+            by_trace
+                .into_iter()
+                .map(|(hash, (bytes, allocations))| (hash, bytes, allocations))
+                .collect()
+        });
+
+        for (hash, bytes, allocations) in by_trace {
+            report.live_bytes += bytes;
+            report.live_allocations += allocations;
+            #[cfg(feature = "backtrace")]
+            match hash
+                .and_then(|hash| Some((self.resolve_backtrace(hash)?, self.trace_mode(hash)?)))
+            {
+                Some((backtrace, mode)) => report.backtraces.push((
+                    backtrace,
+                    ScopeMetric {
+                        live_bytes: bytes,
+                        live_allocations: allocations,
+                        mode,
+                    },
+                )),
+                None => {
+                    report.untraced_bytes += bytes;
+                    report.untraced_allocations += allocations;
+                }
+            }
+        }
+        #[cfg(feature = "backtrace")]
+        report.backtraces.sort_by_key(|x| x.1.live_bytes);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode};
+
+    #[test]
+    fn test_track_thread_scope() {
+        let alloc = AllocTrack::new(System, BacktraceMode::None);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let before = unsafe { alloc.alloc(layout) };
+        let (kept, report) = track_thread_scope(|| unsafe {
+            let freed = alloc.alloc(layout);
+            alloc.dealloc(freed, layout);
+            alloc.alloc(layout)
+        });
+        assert_eq!(report.live_allocations, 1);
+        assert_eq!(report.live_bytes, 64);
+        unsafe {
+            alloc.dealloc(kept, layout);
+            alloc.dealloc(before, layout);
+        }
+    }
+
+    #[test]
+    fn test_track_scope() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc: &'static AllocTrack<System> = Box::leak(Box::new(
+            AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker),
+        ));
+        let (request, requests) = mpsc::channel::<usize>();
+        let (response, responses) = mpsc::channel::<usize>();
+        // allocates a block of the requested size on another thread
+        let thread = std::thread::spawn(move || {
+            for size in requests {
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                response
+                    .send(unsafe { alloc.alloc(layout) } as usize)
+                    .unwrap();
+            }
+        });
+        let alloc_on_thread = |size| {
+            request.send(size).unwrap();
+            (responses.recv().unwrap(), size)
+        };
+        let before = alloc_on_thread(16);
+        let start = now_nanos();
+        let (inside, report) = tracker.track_scope(|| alloc_on_thread(32));
+        let end = now_nanos();
+        let after = alloc_on_thread(64);
+        assert_eq!((report.live_allocations, report.live_bytes), (1, 32));
+
+        // allocations after the scope ended are left out, even if made before the report is collected
+        let report = tracker.scope_report(start..=end, Duration::ZERO, None);
+        assert_eq!((report.live_allocations, report.live_bytes), (1, 32));
+        let report = tracker.scope_report(start..=now_nanos(), Duration::ZERO, None);
+        assert_eq!((report.live_allocations, report.live_bytes), (2, 96));
+
+        drop(request);
+        thread.join().unwrap();
+        for (ptr, size) in [before, inside, after] {
+            unsafe { alloc.dealloc(ptr as *mut u8, Layout::from_size_align(size, 8).unwrap()) };
+        }
+    }
+}
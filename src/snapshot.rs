@@ -0,0 +1,368 @@
+#[cfg(feature = "backtrace")]
+use std::collections::HashMap;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "backtrace")]
+use std::fmt::Write;
+
+#[cfg(feature = "backtrace")]
+use crate::{
+    backtrace_support::{csv_backtrace, fmt_backtrace},
+    untracked, BacktraceMetric, BacktraceMode, HashedBacktrace,
+};
+use crate::{Size, ThreadReport, Tracker, GLOBAL_TRACKER};
+
+/// Per-thread and per-backtrace counters at a moment in time, see `snapshot`.
+#[derive(Clone)]
+pub struct Snapshot {
+    /// When the snapshot was taken
+    pub taken_at: Instant,
+    /// Per-thread counters
+    pub threads: ThreadReport,
+    /// Per-backtrace counters, by backtrace hash
+    #[cfg(feature = "backtrace")]
+    pub backtraces: HashMap<u64, BacktraceMetric>,
+    /// Tracker the snapshot was taken of, to resolve backtraces in a diff
+    #[cfg(feature = "backtrace")]
+    tracker: &'static Tracker,
+}
+
+/// Capture per-thread and per-backtrace counters of the global tracker, see `Tracker::snapshot`
+pub fn snapshot() -> Snapshot {
+    GLOBAL_TRACKER.snapshot()
+}
+
+impl Tracker {
+    /// Capture per-thread and per-backtrace counters, to later compare against another snapshot with `Snapshot::diff`.
+    /// Backtraces are not symbolized until they show up in a diff.
+    pub fn snapshot(&'static self) -> Snapshot {
+        Snapshot {
+            taken_at: Instant::now(),
+            threads: self.thread_report(),
+            #[cfg(feature = "backtrace")]
+            backtraces: untracked(|| {
+                self.maps()
+                    .trace_map
+                    .iter()
+                    .map(|entry| (*entry.key(), entry.metric()))
+                    .collect()
+            }),
+            #[cfg(feature = "backtrace")]
+            tracker: self,
+        }
+    }
+}
+
+/// Helper to display signed sizes
+struct SizeDelta(i64);
+
+impl fmt::Display for SizeDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "+" };
+        write!(f, "{sign}{}", Size(self.0.unsigned_abs()))
+    }
+}
+
+impl Snapshot {
+    /// Compute what changed between this snapshot and a `later` one.
+    /// Only threads and backtraces with any activity in between are included.
+    /// Threads are matched by name, so a thread that exited in between shows up under its exited name.
+    pub fn diff(&self, later: &Snapshot) -> SnapshotDiff {
+        let mut threads = BTreeMap::new();
+        for (name, metric) in &later.threads.0 {
+            let before = self.threads.0.get(name).cloned().unwrap_or_default();
+            let delta = ThreadDelta {
+                new: !self.threads.0.contains_key(name),
+                allocated: metric.total_alloc.saturating_sub(before.total_alloc),
+                did_free: metric.total_did_free.saturating_sub(before.total_did_free),
+                freed: metric.total_freed.saturating_sub(before.total_freed),
+                current_used: metric.current_used as i64 - before.current_used as i64,
+            };
+            if delta.allocated == 0 && delta.did_free == 0 && delta.freed == 0 {
+                continue;
+            }
+            threads.insert(name.clone(), delta);
+        }
+
+        #[cfg(feature = "backtrace")]
+        let backtraces = {
+            let mut backtraces = vec![];
+            for (hash, metric) in &later.backtraces {
+                let before = self.backtraces.get(hash).cloned().unwrap_or_default();
+                let delta = BacktraceDelta {
+                    new: !self.backtraces.contains_key(hash),
+                    allocated: metric.allocated.saturating_sub(before.allocated),
+                    allocations: metric.allocations.saturating_sub(before.allocations),
+                    freed: metric.freed.saturating_sub(before.freed),
+                    frees: metric.frees.saturating_sub(before.frees),
+                    in_use: metric.in_use() as i64 - before.in_use() as i64,
+                    mode: metric.mode,
+                };
+                if delta.allocations == 0 && delta.frees == 0 && delta.in_use == 0 {
+                    continue;
+                }
+                let Some(backtrace) = later.tracker.resolve_backtrace(*hash) else {
+                    continue;
+                };
+                backtraces.push((backtrace, delta));
+            }
+            backtraces.sort_by_key(|x: &(HashedBacktrace, BacktraceDelta)| x.1.in_use);
+            BacktraceDiffReport(backtraces)
+        };
+
+        SnapshotDiff {
+            elapsed: later.taken_at.saturating_duration_since(self.taken_at),
+            threads,
+            #[cfg(feature = "backtrace")]
+            backtraces,
+        }
+    }
+}
+
+/// Change in a thread's counters between two snapshots
+#[derive(Debug, Clone, Default)]
+pub struct ThreadDelta {
+    /// Whether the thread was not in the earlier snapshot
+    pub new: bool,
+    /// Bytes allocated in this thread in between
+    pub allocated: u64,
+    /// Bytes freed in this thread in between
+    pub did_free: u64,
+    /// Bytes allocated in this thread that were freed in between
+    pub freed: u64,
+    /// Change in bytes allocated in this thread that are not freed
+    pub current_used: i64,
+}
+
+impl fmt::Display for ThreadDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.new {
+            writeln!(f, "new thread")?;
+        }
+        writeln!(f, "total_alloc: {}", Size(self.allocated))?;
+        writeln!(f, "total_did_free: {}", Size(self.did_free))?;
+        writeln!(f, "total_freed: {}", Size(self.freed))?;
+        writeln!(f, "current_used: {}", SizeDelta(self.current_used))?;
+        Ok(())
+    }
+}
+
+/// Change in a backtrace's counters between two snapshots
+#[cfg(feature = "backtrace")]
+#[derive(Debug, Clone, Default)]
+pub struct BacktraceDelta {
+    /// Whether the backtrace was not in the earlier snapshot
+    pub new: bool,
+    /// Bytes allocated here in between
+    pub allocated: u64,
+    /// Number of allocations made here in between
+    pub allocations: u64,
+    /// Bytes allocated here that were freed in between
+    pub freed: u64,
+    /// Number of allocations made here that were freed in between
+    pub frees: u64,
+    /// Change in bytes allocated here that are not freed
+    pub in_use: i64,
+    /// `mode` as copied from `AllocTrack`
+    pub mode: BacktraceMode,
+}
+
+#[cfg(feature = "backtrace")]
+impl fmt::Display for BacktraceDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.new {
+            writeln!(f, "new backtrace")?;
+        }
+        writeln!(f, "allocated: {}", Size(self.allocated))?;
+        writeln!(f, "allocations: {}", self.allocations)?;
+        writeln!(f, "freed: {}", Size(self.freed))?;
+        writeln!(f, "frees: {}", self.frees)?;
+        writeln!(f, "total_used: {}", SizeDelta(self.in_use))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "backtrace")]
+impl BacktraceDelta {
+    /// Average size of the allocations made here in between
+    pub fn avg_allocation(&self) -> f64 {
+        if self.allocations == 0 {
+            0.0
+        } else {
+            self.allocated as f64 / self.allocations as f64
+        }
+    }
+
+    /// Writes the columns of `BacktraceMetric::csv_write`, with `total_used` as the change in bytes in use
+    pub fn csv_write(&self, out: &mut impl Write) -> fmt::Result {
+        write!(
+            out,
+            "{},{},{},{},{}",
+            self.allocated,
+            self.allocations,
+            self.avg_allocation(),
+            self.freed,
+            self.in_use
+        )?;
+        Ok(())
+    }
+}
+
+/// A report of all backtraces that changed between two snapshots, sorted by change in bytes in use.
+#[cfg(feature = "backtrace")]
+pub struct BacktraceDiffReport(pub Vec<(HashedBacktrace, BacktraceDelta)>);
+
+#[cfg(feature = "backtrace")]
+impl BacktraceDiffReport {
+    /// Same columns as `BacktraceReport::csv`, so that both can be loaded by the same tooling
+    pub fn csv(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            &mut out,
+            "allocated,allocations,avg_allocation,freed,total_used,backtrace"
+        )
+        .unwrap();
+        for (backtrace, delta) in &self.0 {
+            delta.csv_write(&mut out).unwrap();
+            writeln!(&mut out, ",{}", csv_backtrace(backtrace, delta.mode)).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(feature = "backtrace")]
+impl fmt::Display for BacktraceDiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (backtrace, delta) in &self.0 {
+            fmt_backtrace(f, backtrace, delta.mode)?;
+            writeln!(f, "\n{delta}\n\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Changes between two snapshots, see `Snapshot::diff`
+pub struct SnapshotDiff {
+    /// Time between the two snapshots
+    pub elapsed: Duration,
+    /// Threads with any activity in between, by name
+    pub threads: BTreeMap<String, ThreadDelta>,
+    /// Backtraces with any activity in between
+    #[cfg(feature = "backtrace")]
+    pub backtraces: BacktraceDiffReport,
+}
+
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "elapsed: {:.03}s\n", self.elapsed.as_secs_f64())?;
+        for (name, delta) in &self.threads {
+            writeln!(f, "{name}:\n{delta}\n")?;
+        }
+        #[cfg(feature = "backtrace")]
+        write!(f, "{}", self.backtraces)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadMetric;
+
+    fn thread_snapshot(threads: &[(&str, u64, u64)]) -> Snapshot {
+        Snapshot {
+            taken_at: Instant::now(),
+            threads: ThreadReport(
+                threads
+                    .iter()
+                    .map(|(name, total_alloc, total_freed)| {
+                        let metric = ThreadMetric {
+                            total_alloc: *total_alloc,
+                            total_did_free: *total_freed,
+                            total_freed: *total_freed,
+                            current_used: total_alloc - total_freed,
+                            ..Default::default()
+                        };
+                        (name.to_string(), metric)
+                    })
+                    .collect(),
+            ),
+            #[cfg(feature = "backtrace")]
+            backtraces: HashMap::new(),
+            #[cfg(feature = "backtrace")]
+            tracker: &GLOBAL_TRACKER,
+        }
+    }
+
+    #[test]
+    fn test_thread_diff() {
+        let before = thread_snapshot(&[("main", 100, 50), ("idle", 10, 0)]);
+        let after = thread_snapshot(&[("main", 150, 120), ("idle", 10, 0), ("worker", 30, 0)]);
+        let diff = before.diff(&after);
+
+        assert_eq!(diff.threads.len(), 2);
+        let main = &diff.threads["main"];
+        assert!(!main.new);
+        assert_eq!(main.allocated, 50);
+        assert_eq!(main.freed, 70);
+        assert_eq!(main.current_used, -20);
+        let worker = &diff.threads["worker"];
+        assert!(worker.new);
+        assert_eq!(worker.current_used, 30);
+    }
+
+    #[test]
+    #[cfg(feature = "backtrace")]
+    fn test_backtrace_diff() {
+        use std::alloc::{GlobalAlloc, Layout, System};
+
+        use crate::AllocTrack;
+
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::Short).with_tracker(tracker);
+        let kept = Layout::from_size_align(100, 8).unwrap();
+        let added = Layout::from_size_align(50, 8).unwrap();
+        let mut kept_ptrs = (0..2)
+            .map(|_| unsafe { alloc.alloc(kept) })
+            .collect::<Vec<_>>();
+        let before = tracker.snapshot();
+        unsafe { alloc.dealloc(kept_ptrs.pop().unwrap(), kept) };
+        let added_ptrs = (0..3)
+            .map(|_| unsafe { alloc.alloc(added) })
+            .collect::<Vec<_>>();
+        let after = tracker.snapshot();
+
+        let diff = before.diff(&after);
+        let [(_, freed), (_, new)] = &diff.backtraces.0[..] else {
+            panic!("expected two backtraces, got {}", diff.backtraces.0.len());
+        };
+        assert!(!freed.new);
+        assert_eq!((freed.allocated, freed.allocations), (0, 0));
+        assert_eq!((freed.freed, freed.frees, freed.in_use), (100, 1, -100));
+        assert!(new.new);
+        assert_eq!((new.allocated, new.allocations), (150, 3));
+        assert_eq!((new.freed, new.frees, new.in_use), (0, 0, 150));
+
+        let csv = diff.backtraces.csv();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("allocated,allocations,avg_allocation,freed,total_used,backtrace")
+        );
+        assert!(lines.next().unwrap().starts_with("0,0,0,100,-100,"));
+        assert!(lines.next().unwrap().starts_with("150,3,50,0,150,"));
+
+        // nothing changed since
+        assert!(after.diff(&tracker.snapshot()).backtraces.0.is_empty());
+        for ptr in kept_ptrs {
+            unsafe { alloc.dealloc(ptr, kept) };
+        }
+        for ptr in added_ptrs {
+            unsafe { alloc.dealloc(ptr, added) };
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use dashmap::DashMap;
+
+use crate::{enter_alloc, untracked, LiveCounters, PeakMetric, Size, Tracker, GLOBAL_TRACKER};
+
+/// Tag id of allocations made outside of any tag
+pub(crate) const NO_TAG: usize = 0;
+
+/// Separator between nested tags in `tag_report`
+pub const TAG_PATH_SEPARATOR: &str = "/";
+
+lazy_static::lazy_static! {
+    /// Names of all tags, tasks and spans by id
+    pub(crate) static ref TAG_NAMES: TagNames = TagNames::new();
+}
+
+thread_local! {
+    /// Innermost tag of the current thread
+    static CURRENT_TAG: Cell<usize> = const { Cell::new(NO_TAG) };
+}
+
+/// Innermost tag of the current thread, to be stored with an allocation
+pub(crate) fn current_tag() -> usize {
+    CURRENT_TAG.with(|x| x.get())
+}
+
+struct TagName {
+    parent: usize,
+    target: &'static str,
+    name: &'static str,
+}
+
+/// Ids of named, nestable attribution targets, shared by all trackers.
+/// Nested tags are identified by their parent's id, target and name.
+pub(crate) struct TagNames {
+    /// tag id -> name of the tag
+    names: DashMap<usize, TagName>,
+    /// (parent tag id, target, name) -> tag id
+    ids: DashMap<(usize, &'static str, &'static str), usize>,
+    next_id: AtomicUsize,
+}
+
+impl TagNames {
+    fn new() -> Self {
+        Self {
+            names: DashMap::new(),
+            ids: DashMap::new(),
+            next_id: AtomicUsize::new(NO_TAG + 1),
+        }
+    }
+
+    /// Id of the tag `name` nested in `parent`, registering it if needed.
+    /// A nonempty `target` is reported as a prefix of `name`, i.e. `target::name`.
+    pub fn id(&self, parent: usize, target: &'static str, name: &'static str) -> usize {
+        enter_alloc(|| {
+            if let Some(id) = self.ids.get(&(parent, target, name)) {
+                return *id;
+            }
+            *self.ids.entry((parent, target, name)).or_insert_with(|| {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                self.names.insert(
+                    id,
+                    TagName {
+                        parent,
+                        target,
+                        name,
+                    },
+                );
+                id
+            })
+        })
+    }
+
+    /// Whether `tag` is `ancestor` or nested in it. Must be called with `IN_ALLOC` set.
+    pub fn is_within(&self, mut tag: usize, ancestor: usize) -> bool {
+        while tag != NO_TAG {
+            if tag == ancestor {
+                return true;
+            }
+            let Some(parent) = self.names.get(&tag).map(|x| x.parent) else {
+                return false;
+            };
+            tag = parent;
+        }
+        false
+    }
+
+    /// Paths of all tags by id, nested names joined by `TAG_PATH_SEPARATOR`. Must be called with `IN_ALLOC` set.
+    fn paths(&self) -> BTreeMap<usize, String> {
+        // copied first, as looking up parents while iterating could deadlock against a concurrent `id`
+        let names: BTreeMap<usize, (usize, String)> = self
+            .names
+            .iter()
+            .map(|tag| {
+                let name = if tag.target.is_empty() {
+                    tag.name.to_string()
+                } else {
+                    format!("{}::{}", tag.target, tag.name)
+                };
+                (*tag.key(), (tag.parent, name))
+            })
+            .collect();
+        names
+            .iter()
+            .map(|(id, (parent, name))| {
+                let mut path = vec![&**name];
+                let mut parent = *parent;
+                while let Some((grandparent, name)) = names.get(&parent) {
+                    path.push(name);
+                    parent = *grandparent;
+                }
+                path.reverse();
+                (*id, path.join(TAG_PATH_SEPARATOR))
+            })
+            .collect()
+    }
+}
+
+struct TagInfo {
+    parent: usize,
+    allocated: AtomicUsize,
+    freed: AtomicUsize,
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    live: LiveCounters,
+}
+
+/// Allocation counters of a tracker per tag, task or span.
+/// Allocations of nested tags also count towards their parents.
+pub(crate) struct TagCounters {
+    /// tag id -> counters of the tag, including nested tags
+    infos: DashMap<usize, TagInfo>,
+}
+
+impl TagCounters {
+    pub fn new() -> Self {
+        Self {
+            infos: DashMap::new(),
+        }
+    }
+
+    /// Calls `func` on the counters of `tag` and all tags it is nested in. Must be called with `IN_ALLOC` set.
+    fn for_path(&self, mut tag: usize, func: impl Fn(&TagInfo)) {
+        while tag != NO_TAG {
+            if let Some(info) = self.infos.get(&tag) {
+                func(&info);
+                tag = info.parent;
+                continue;
+            }
+            let Some(parent) = TAG_NAMES.names.get(&tag).map(|x| x.parent) else {
+                return;
+            };
+            self.infos.entry(tag).or_insert_with(|| TagInfo {
+                parent,
+                allocated: AtomicUsize::new(0),
+                freed: AtomicUsize::new(0),
+                allocations: AtomicUsize::new(0),
+                frees: AtomicUsize::new(0),
+                live: LiveCounters::new(),
+            });
+        }
+    }
+
+    /// Account an allocation of `size` bytes in `tag`. Must be called with `IN_ALLOC` set.
+    pub fn alloc(&self, tag: usize, size: usize) {
+        self.for_path(tag, |info| {
+            info.allocated.fetch_add(size, Ordering::Relaxed);
+            info.allocations.fetch_add(1, Ordering::Relaxed);
+            info.live.add(size, 1);
+        });
+    }
+
+    /// Account a free of `size` bytes allocated in `tag`. Must be called with `IN_ALLOC` set.
+    pub fn free(&self, tag: usize, size: usize) {
+        self.for_path(tag, |info| {
+            info.freed.fetch_add(size, Ordering::Relaxed);
+            info.frees.fetch_add(1, Ordering::Relaxed);
+            info.live.remove(size, 1);
+        });
+    }
+
+    /// Account a reallocation of a block allocated in `tag`. Must be called with `IN_ALLOC` set.
+    pub fn realloc(&self, tag: usize, old_size: usize, new_size: usize) {
+        self.for_path(tag, |info| {
+            if new_size >= old_size {
+                info.allocated
+                    .fetch_add(new_size - old_size, Ordering::Relaxed);
+                info.live.add(new_size - old_size, 0);
+            } else {
+                info.freed.fetch_add(old_size - new_size, Ordering::Relaxed);
+                info.live.remove(old_size - new_size, 0);
+            }
+        });
+    }
+
+    /// Live bytes of `tag`, including nested tags. Must be called with `IN_ALLOC` set.
+    pub fn live_bytes(&self, tag: usize) -> usize {
+        self.infos
+            .get(&tag)
+            .map(|info| info.live.load().bytes)
+            .unwrap_or(0)
+    }
+
+    pub fn reset_peaks(&self) {
+        for info in self.infos.iter() {
+            info.live.reset_peaks();
+        }
+    }
+
+    /// Metrics of all tags by path, nested names joined by `TAG_PATH_SEPARATOR`
+    pub fn metrics(&self) -> BTreeMap<String, TagMetric> {
+        untracked(|| {
+            let paths = TAG_NAMES.paths();
+            self.infos
+                .iter()
+                .filter_map(|info| {
+                    let metric = TagMetric {
+                        allocated: info.allocated.load(Ordering::Relaxed) as u64,
+                        freed: info.freed.load(Ordering::Relaxed) as u64,
+                        allocations: info.allocations.load(Ordering::Relaxed) as u64,
+                        frees: info.frees.load(Ordering::Relaxed) as u64,
+                        peak: info.live.load().peak(),
+                    };
+                    Some((paths.get(info.key())?.clone(), metric))
+                })
+                .collect()
+        })
+    }
+}
+
+/// Attributes allocations made on the current thread to a tag until dropped, see `push_tag`
+pub struct TagGuard {
+    previous: usize,
+    /// tags are per-thread, so the guard must be dropped on the thread that created it
+    _marker: PhantomData<*const ()>,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        CURRENT_TAG.with(|x| x.set(self.previous));
+    }
+}
+
+/// Attribute allocations made on the current thread to the tag `name` until the returned guard is dropped.
+/// Tags nest: pushing `"lru"` while `"cache"` is active attributes allocations to `"cache/lru"`, which also count towards `"cache"`.
+/// Guards must be dropped in reverse order of creation.
+pub fn push_tag(name: &'static str) -> TagGuard {
+    let previous = current_tag();
+    CURRENT_TAG.with(|x| x.set(TAG_NAMES.id(previous, "", name)));
+    TagGuard {
+        previous,
+        _marker: PhantomData,
+    }
+}
+
+/// Run `func` with allocations made on the current thread attributed to the tag `name`, see `push_tag`.
+pub fn with_tag<T>(name: &'static str, func: impl FnOnce() -> T) -> T {
+    let _guard = push_tag(name);
+    func()
+}
+
+/// Allocation metrics of a tag, including all tags nested in it, or of a task
+#[derive(Debug, Clone, Default)]
+pub struct TagMetric {
+    /// Bytes allocated with this tag active
+    pub allocated: u64,
+    /// Bytes allocated with this tag active that have been freed
+    pub freed: u64,
+    /// Number of allocations made with this tag active
+    pub allocations: u64,
+    /// Number of allocations made with this tag active that have been freed
+    pub frees: u64,
+    /// Peak of `in_use` and `live_allocations`
+    pub peak: PeakMetric,
+}
+
+impl TagMetric {
+    /// Number of bytes currently allocated and not freed
+    pub fn in_use(&self) -> u64 {
+        self.allocated.saturating_sub(self.freed)
+    }
+
+    /// Number of allocations that are not freed
+    pub fn live_allocations(&self) -> u64 {
+        self.allocations.saturating_sub(self.frees)
+    }
+}
+
+impl fmt::Display for TagMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "allocated: {}", Size(self.allocated))?;
+        writeln!(f, "allocations: {}", self.allocations)?;
+        writeln!(f, "freed: {}", Size(self.freed))?;
+        writeln!(f, "total_used: {}", Size(self.in_use()))?;
+        writeln!(f, "live_allocations: {}", self.live_allocations())?;
+        writeln!(f, "peak_used: {}", self.peak)?;
+        Ok(())
+    }
+}
+
+/// A report of all tags by path, i.e. `"cache/lru"`
+#[derive(Clone)]
+pub struct TagReport(pub BTreeMap<String, TagMetric>);
+
+impl fmt::Display for TagReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (path, metric) in &self.0 {
+            writeln!(f, "{path}:\n{metric}\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Generate a report of allocations by tag of the global tracker, see `Tracker::tag_report`
+pub fn tag_report() -> TagReport {
+    GLOBAL_TRACKER.tag_report()
+}
+
+impl Tracker {
+    /// Generate a report of allocations by tag, see `with_tag`.
+    /// Note that the numbers are not a synchronized snapshot, and have slight timing skew.
+    pub fn tag_report(&'static self) -> TagReport {
+        TagReport(enter_alloc(|| self.maps()).tags.metrics())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode};
+
+    #[test]
+    fn test_nested_tags() {
+        let alloc = AllocTrack::new(System, BacktraceMode::None);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let (outer, inner) = with_tag("test_outer", || unsafe {
+            let outer = alloc.alloc(layout);
+            let inner = with_tag("inner", || alloc.alloc(layout));
+            (outer, inner)
+        });
+        assert_eq!(current_tag(), NO_TAG);
+        unsafe { alloc.dealloc(inner, layout) };
+
+        let report = tag_report();
+        let outer_metric = &report.0["test_outer"];
+        assert_eq!(outer_metric.allocations, 2);
+        assert_eq!(outer_metric.in_use(), 32);
+        assert_eq!(outer_metric.peak.bytes, 64);
+        let inner_metric = &report.0["test_outer/inner"];
+        assert_eq!(inner_metric.allocations, 1);
+        assert_eq!(inner_metric.in_use(), 0);
+        unsafe { alloc.dealloc(outer, layout) };
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{
+    enter_alloc,
+    tags::{TagMetric, NO_TAG, TAG_NAMES},
+    Tracker, GLOBAL_TRACKER,
+};
+
+thread_local! {
+    /// Task currently being polled on this thread
+    static CURRENT_TASK: Cell<usize> = const { Cell::new(NO_TAG) };
+}
+
+/// Task currently being polled on this thread, to be stored with an allocation
+pub(crate) fn current_task() -> usize {
+    CURRENT_TASK.with(|x| x.get())
+}
+
+/// A future whose allocations are attributed to a named task, see `TrackAllocs::track_allocs`
+pub struct Tracked<F> {
+    inner: F,
+    task: usize,
+}
+
+impl<F: Future> Future for Tracked<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let task = self.task;
+        // `inner` is never moved out of the pinned `Tracked`
+        let inner = unsafe { self.map_unchecked_mut(|x| &mut x.inner) };
+        let _guard = TaskGuard(CURRENT_TASK.with(|x| x.replace(task)));
+        inner.poll(cx)
+    }
+}
+
+/// Restores the previously polled task, also when the polled future panics
+struct TaskGuard(usize);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        CURRENT_TASK.with(|x| x.set(self.0));
+    }
+}
+
+/// Extension trait to attribute the allocations of a future to a named task
+pub trait TrackAllocs: Future + Sized {
+    /// Attribute allocations made while polling this future to the task `name`, regardless of which thread polls it.
+    /// Frees are accounted to the task that made the allocation. Tasks sharing a name are reported together in `task_report`.
+    fn track_allocs(self, name: &'static str) -> Tracked<Self> {
+        Tracked {
+            inner: self,
+            task: TAG_NAMES.id(NO_TAG, "", name),
+        }
+    }
+}
+
+impl<F: Future> TrackAllocs for F {}
+
+/// A report of all tasks by name, see `TrackAllocs::track_allocs`
+#[derive(Clone)]
+pub struct TaskReport(pub BTreeMap<String, TagMetric>);
+
+impl fmt::Display for TaskReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, metric) in &self.0 {
+            writeln!(f, "{name}:\n{metric}\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Generate a report of allocations by task of the global tracker, see `Tracker::task_report`
+pub fn task_report() -> TaskReport {
+    GLOBAL_TRACKER.task_report()
+}
+
+impl Tracker {
+    /// Generate a report of allocations by task, see `TrackAllocs::track_allocs`.
+    /// Note that the numbers are not a synchronized snapshot, and have slight timing skew.
+    pub fn task_report(&'static self) -> TaskReport {
+        TaskReport(enter_alloc(|| self.maps()).tasks.metrics())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        sync::Arc,
+        task::Wake,
+    };
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode};
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    fn test_tracked_future() {
+        let alloc = AllocTrack::new(System, BacktraceMode::None);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let mut future =
+            std::pin::pin!(async { unsafe { alloc.alloc(layout) } }.track_allocs("test_task"));
+        let waker = Arc::new(NoopWaker).into();
+        let Poll::Ready(ptr) = future.as_mut().poll(&mut Context::from_waker(&waker)) else {
+            panic!("future not ready");
+        };
+        assert_eq!(current_task(), NO_TAG);
+
+        let metric = &task_report().0["test_task"];
+        assert_eq!(metric.allocations, 1);
+        assert_eq!(metric.in_use(), 24);
+        unsafe { alloc.dealloc(ptr, layout) };
+        assert_eq!(task_report().0["test_task"].in_use(), 0);
+    }
+}
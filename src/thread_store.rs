@@ -0,0 +1,667 @@
+use std::{
+    collections::BTreeMap,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::SystemTime,
+};
+
+use crate::{
+    enter_alloc,
+    histogram::{
+        AtomicHistogram, LifetimeHistogram, SizeHistogram, LIFETIME_BUCKETS, SIZE_BUCKETS,
+    },
+    tracker::for_each_tracker,
+    LiveCounters, LiveUsage, ReallocMetric,
+};
+
+/// Number of slots in the first chunk, every following chunk is twice the size of the previous one.
+const FIRST_CHUNK_SLOTS: usize = 64;
+/// With doubling chunk sizes, this is far more concurrently live threads than any OS supports.
+const MAX_CHUNKS: usize = 48;
+
+/// Slot that the counters of exited threads are folded into.
+/// Threads that allocate after their thread locals are destroyed are also accounted here.
+pub(crate) const RETIRED_SLOT: usize = 0;
+/// Thread id of `RETIRED_SLOT`
+pub(crate) const RETIRED_UID: usize = usize::MAX;
+
+/// next thread id incrementor, 0 marks an unowned slot
+static THREAD_ID_COUNTER: AtomicUsize = AtomicUsize::new(1);
+
+/// One past the highest slot index ever handed out to a thread. Slot indices are shared by all trackers.
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(RETIRED_SLOT + 1);
+
+/// Slots released by exited threads
+static FREE_SLOTS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// A non-atomic copy of the counters of a `ThreadStore`
+#[derive(Clone, Default)]
+pub(crate) struct ThreadCounters {
+    pub alloc: usize,
+    pub did_free: usize,
+    pub self_freed: usize,
+    pub freed_by_others: BTreeMap<usize, usize>,
+    pub realloc: ReallocMetric,
+    pub failed_allocs: usize,
+    pub failed_bytes: usize,
+    pub live: LiveUsage,
+    pub lifetimes: LifetimeHistogram,
+    pub sizes: SizeHistogram,
+}
+
+/// An exited thread, with its counters as snapshotted when it exited.
+/// Frees of its allocations by other threads keep being accounted here.
+#[derive(Clone)]
+pub(crate) struct ExitedThread {
+    pub name: String,
+    pub exited_at: SystemTime,
+    pub counters: ThreadCounters,
+}
+
+/// Representation of globally-accessible TLS
+pub(crate) struct ThreadStore {
+    /// Unique id of the thread currently owning this slot, never reused. 0 if the slot is unowned.
+    pub uid: AtomicUsize,
+    /// OS thread id of the owning thread
+    #[allow(dead_code)]
+    pub tid: AtomicU32,
+    /// Bytes allocated by this thread
+    pub alloc: AtomicUsize,
+    /// Bytes freed by this thread, regardless of which thread allocated them
+    pub did_free: AtomicUsize,
+    /// Bytes allocated by this thread that have been freed by this thread
+    pub self_freed: AtomicUsize,
+    /// Bytes allocated by this thread that have been freed by other threads, by uid of the freeing thread.
+    /// Also serializes remote frees against retirement of the slot.
+    pub freed_by_others: Mutex<BTreeMap<usize, usize>>,
+    /// Number of reallocations done by this thread
+    pub reallocs: AtomicUsize,
+    /// Number of reallocations done by this thread that moved the allocation
+    pub realloc_moves: AtomicUsize,
+    /// Bytes added by reallocations done by this thread
+    pub realloc_grown: AtomicUsize,
+    /// Bytes removed by reallocations done by this thread
+    pub realloc_shrunk: AtomicUsize,
+    /// Number of allocations or reallocations by this thread that failed
+    pub failed_allocs: AtomicUsize,
+    /// Bytes requested by allocations or reallocations by this thread that failed
+    pub failed_bytes: AtomicUsize,
+    /// Allocations by this thread that are not freed
+    pub live: LiveCounters,
+    /// Lifetimes of freed allocations made by this thread
+    pub lifetimes: AtomicHistogram<LIFETIME_BUCKETS>,
+    /// Sizes of allocations and reallocations made by this thread
+    pub sizes: AtomicHistogram<SIZE_BUCKETS>,
+}
+
+impl ThreadStore {
+    const fn new() -> Self {
+        Self {
+            uid: AtomicUsize::new(0),
+            tid: AtomicU32::new(0),
+            alloc: AtomicUsize::new(0),
+            did_free: AtomicUsize::new(0),
+            self_freed: AtomicUsize::new(0),
+            freed_by_others: Mutex::new(BTreeMap::new()),
+            reallocs: AtomicUsize::new(0),
+            realloc_moves: AtomicUsize::new(0),
+            realloc_grown: AtomicUsize::new(0),
+            realloc_shrunk: AtomicUsize::new(0),
+            failed_allocs: AtomicUsize::new(0),
+            failed_bytes: AtomicUsize::new(0),
+            live: LiveCounters::new(),
+            lifetimes: AtomicHistogram::new(),
+            sizes: AtomicHistogram::new(),
+        }
+    }
+
+    pub fn lock_freed_by_others(&self) -> MutexGuard<'_, BTreeMap<usize, usize>> {
+        self.freed_by_others
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    /// Copy the counters of this slot
+    pub fn counters(&self) -> ThreadCounters {
+        let freed_by_others = self.lock_freed_by_others();
+        ThreadCounters {
+            alloc: self.alloc.load(Ordering::Relaxed),
+            did_free: self.did_free.load(Ordering::Relaxed),
+            self_freed: self.self_freed.load(Ordering::Relaxed),
+            freed_by_others: freed_by_others.clone(),
+            realloc: ReallocMetric {
+                reallocs: self.reallocs.load(Ordering::Relaxed) as u64,
+                moves: self.realloc_moves.load(Ordering::Relaxed) as u64,
+                grown: self.realloc_grown.load(Ordering::Relaxed) as u64,
+                shrunk: self.realloc_shrunk.load(Ordering::Relaxed) as u64,
+            },
+            failed_allocs: self.failed_allocs.load(Ordering::Relaxed),
+            failed_bytes: self.failed_bytes.load(Ordering::Relaxed),
+            live: self.live.load(),
+            lifetimes: self.lifetimes.load_lifetimes(),
+            sizes: self.sizes.load_sizes(),
+        }
+    }
+
+    /// Move the counters out of this slot, leaving it zeroed.
+    /// The caller must hold the `freed_by_others` lock, passed in as `freed_by_others`.
+    fn take_counters(&self, freed_by_others: &mut BTreeMap<usize, usize>) -> ThreadCounters {
+        ThreadCounters {
+            alloc: self.alloc.swap(0, Ordering::Relaxed),
+            did_free: self.did_free.swap(0, Ordering::Relaxed),
+            self_freed: self.self_freed.swap(0, Ordering::Relaxed),
+            freed_by_others: std::mem::take(freed_by_others),
+            realloc: ReallocMetric {
+                reallocs: self.reallocs.swap(0, Ordering::Relaxed) as u64,
+                moves: self.realloc_moves.swap(0, Ordering::Relaxed) as u64,
+                grown: self.realloc_grown.swap(0, Ordering::Relaxed) as u64,
+                shrunk: self.realloc_shrunk.swap(0, Ordering::Relaxed) as u64,
+            },
+            failed_allocs: self.failed_allocs.swap(0, Ordering::Relaxed),
+            failed_bytes: self.failed_bytes.swap(0, Ordering::Relaxed),
+            live: self.live.take(),
+            lifetimes: self.lifetimes.take_lifetimes(),
+            sizes: self.sizes.take_sizes(),
+        }
+    }
+
+    /// Add `counters` onto this slot. `self_uid` is the uid that frees by the owner of `counters` are credited to.
+    fn add_counters(&self, counters: ThreadCounters, self_uid: usize) {
+        self.alloc.fetch_add(counters.alloc, Ordering::Relaxed);
+        self.did_free
+            .fetch_add(counters.did_free, Ordering::Relaxed);
+        self.reallocs
+            .fetch_add(counters.realloc.reallocs as usize, Ordering::Relaxed);
+        self.realloc_moves
+            .fetch_add(counters.realloc.moves as usize, Ordering::Relaxed);
+        self.realloc_grown
+            .fetch_add(counters.realloc.grown as usize, Ordering::Relaxed);
+        self.realloc_shrunk
+            .fetch_add(counters.realloc.shrunk as usize, Ordering::Relaxed);
+        self.failed_allocs
+            .fetch_add(counters.failed_allocs, Ordering::Relaxed);
+        self.failed_bytes
+            .fetch_add(counters.failed_bytes, Ordering::Relaxed);
+        self.live.merge(&counters.live);
+        self.lifetimes.merge_lifetimes(&counters.lifetimes);
+        self.sizes.merge_sizes(&counters.sizes);
+        let mut freed_by_others = self.lock_freed_by_others();
+        if counters.self_freed != 0 {
+            *freed_by_others.entry(self_uid).or_default() += counters.self_freed;
+        }
+        for (uid, freed) in counters.freed_by_others {
+            *freed_by_others.entry(uid).or_default() += freed;
+        }
+    }
+}
+
+/// Custom psuedo-TLS implementation that allows safe global introspection, one per tracker.
+/// Slots are stored in chunks that are allocated as needed and never freed, so references to slots are `'static`.
+/// Slots of exited threads are reused by new threads.
+pub(crate) struct ThreadStoreTable {
+    chunks: [AtomicPtr<ThreadStore>; MAX_CHUNKS],
+    /// One past the highest slot index ever used in this table
+    len: AtomicUsize,
+    /// Exited threads that are still reported individually, by uid
+    exited_threads: Mutex<BTreeMap<usize, ExitedThread>>,
+    /// Maximum length of `exited_threads`, the oldest exited threads are folded into `RETIRED_SLOT` beyond it.
+    exited_thread_retention: AtomicUsize,
+}
+
+fn chunk_position(index: usize) -> (usize, usize) {
+    let biased = index + FIRST_CHUNK_SLOTS;
+    let chunk = (biased.ilog2() - FIRST_CHUNK_SLOTS.ilog2()) as usize;
+    (chunk, biased - (FIRST_CHUNK_SLOTS << chunk))
+}
+
+impl ThreadStoreTable {
+    pub const fn new() -> Self {
+        Self {
+            chunks: [const { AtomicPtr::new(null_mut()) }; MAX_CHUNKS],
+            len: AtomicUsize::new(RETIRED_SLOT + 1),
+            exited_threads: Mutex::new(BTreeMap::new()),
+            exited_thread_retention: AtomicUsize::new(256),
+        }
+    }
+
+    /// Number of slots that may be in use
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    pub fn lock_exited_threads(&self) -> MutexGuard<'_, BTreeMap<usize, ExitedThread>> {
+        self.exited_threads
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    /// Set how many exited threads are retained individually, folding any excess into `RETIRED_SLOT`.
+    /// Must be called with `IN_ALLOC` set.
+    pub fn set_exited_thread_retention(&self, limit: usize) {
+        self.exited_thread_retention.store(limit, Ordering::Relaxed);
+        let mut exited_threads = self.lock_exited_threads();
+        while exited_threads.len() > limit {
+            let (_, oldest) = exited_threads.pop_first().unwrap();
+            self.retire(oldest);
+        }
+    }
+
+    /// Get a slot if its chunk has been allocated
+    pub fn get(&self, index: usize) -> Option<&'static ThreadStore> {
+        let (chunk, offset) = chunk_position(index);
+        let chunk = self.chunks[chunk].load(Ordering::Acquire);
+        if chunk.is_null() {
+            return None;
+        }
+        Some(unsafe { &*chunk.add(offset) })
+    }
+
+    /// Get a slot, allocating its chunk if needed. Must be called with `IN_ALLOC` set.
+    pub fn get_or_create(&self, index: usize) -> &'static ThreadStore {
+        self.len.fetch_max(index + 1, Ordering::AcqRel);
+        if let Some(slot) = self.get(index) {
+            return slot;
+        }
+        let (chunk, _) = chunk_position(index);
+        let slots: Box<[ThreadStore]> = (0..FIRST_CHUNK_SLOTS << chunk)
+            .map(|_| ThreadStore::new())
+            .collect();
+        let new_chunk = Box::into_raw(slots) as *mut ThreadStore;
+        if self.chunks[chunk]
+            .compare_exchange(null_mut(), new_chunk, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            // another thread allocated this chunk first
+            drop(unsafe {
+                Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+                    new_chunk,
+                    FIRST_CHUNK_SLOTS << chunk,
+                ))
+            });
+        }
+        self.get(index).unwrap()
+    }
+
+    /// Slot of the current thread, claiming it for the current thread if this is its first use in this table.
+    /// Must be called with `IN_ALLOC` set.
+    pub fn current_slot(&self) -> (ThreadRef, &'static ThreadStore) {
+        let current = current_thread();
+        let slot = self.get_or_create(current.slot);
+        if current.slot != RETIRED_SLOT && slot.uid.load(Ordering::Relaxed) != current.uid {
+            #[cfg(feature = "fs")]
+            slot.tid
+                .store(unsafe { crate::get_sys_tid() }, Ordering::Relaxed);
+            slot.uid.store(current.uid, Ordering::Release);
+        }
+        (current, slot)
+    }
+
+    /// Snapshot the counters of an exiting thread named `name` into `exited_threads`, if it used this table.
+    fn release(&self, handle: &ThreadHandle, name: &str) {
+        let Some(slot) = self.get(handle.slot) else {
+            return;
+        };
+        if slot.uid.load(Ordering::Acquire) != handle.uid {
+            return;
+        }
+        let mut freed_by_others = slot.lock_freed_by_others();
+        let exited = ExitedThread {
+            name: name.to_string(),
+            exited_at: SystemTime::now(),
+            counters: slot.take_counters(&mut freed_by_others),
+        };
+        let mut exited_threads = self.lock_exited_threads();
+        exited_threads.insert(handle.uid, exited);
+        slot.tid.store(0, Ordering::Relaxed);
+        slot.uid.store(0, Ordering::Release);
+        while exited_threads.len() > self.exited_thread_retention.load(Ordering::Relaxed) {
+            let (_, oldest) = exited_threads.pop_first().unwrap();
+            self.retire(oldest);
+        }
+    }
+
+    /// Fold the counters of an exited thread into `RETIRED_SLOT`
+    pub fn retire(&self, thread: ExitedThread) {
+        self.get_or_create(RETIRED_SLOT)
+            .add_counters(thread.counters, RETIRED_UID);
+    }
+
+    /// Account `size` bytes allocated by the thread `owner_uid` in `owner_slot` as freed by the current thread.
+    /// `lifetime_nanos` is how long the allocation was live, if it is not just changing ownership.
+    /// Must be called with `IN_ALLOC` set.
+    pub fn free(
+        &self,
+        owner_slot: usize,
+        owner_uid: usize,
+        size: usize,
+        lifetime_nanos: Option<u64>,
+    ) {
+        let (current, slot) = self.current_slot();
+        slot.did_free.fetch_add(size, Ordering::Relaxed);
+        let owner = self.get_or_create(owner_slot);
+        if owner_uid == current.uid {
+            owner.self_freed.fetch_add(size, Ordering::Relaxed);
+            owner.live.remove(size, 1);
+            if let Some(lifetime_nanos) = lifetime_nanos {
+                owner.lifetimes.record_lifetime(lifetime_nanos);
+            }
+            return;
+        }
+        let mut freed_by_others = owner.lock_freed_by_others();
+        if owner.uid.load(Ordering::Acquire) == owner_uid {
+            *freed_by_others.entry(current.uid).or_default() += size;
+            owner.live.remove(size, 1);
+            if let Some(lifetime_nanos) = lifetime_nanos {
+                owner.lifetimes.record_lifetime(lifetime_nanos);
+            }
+            return;
+        }
+        // the owning thread has exited
+        drop(freed_by_others);
+        if let Some(exited) = self.lock_exited_threads().get_mut(&owner_uid) {
+            let counters = &mut exited.counters;
+            *counters.freed_by_others.entry(current.uid).or_default() += size;
+            counters.live.remove(size, 1);
+            if let Some(lifetime_nanos) = lifetime_nanos {
+                counters.lifetimes.record(lifetime_nanos, 1);
+            }
+            return;
+        }
+        let retired = self.get_or_create(RETIRED_SLOT);
+        *retired
+            .lock_freed_by_others()
+            .entry(current.uid)
+            .or_default() += size;
+        retired.live.remove(size, 1);
+        if let Some(lifetime_nanos) = lifetime_nanos {
+            retired.lifetimes.record_lifetime(lifetime_nanos);
+        }
+    }
+
+    /// Account `size` bytes allocated by the current thread, which is returned.
+    /// Must be called with `IN_ALLOC` set.
+    pub fn alloc(&self, size: usize) -> ThreadRef {
+        let (current, slot) = self.current_slot();
+        slot.alloc.fetch_add(size, Ordering::Relaxed);
+        slot.live.add(size, 1);
+        slot.sizes.record_size(size);
+        current
+    }
+
+    /// Reset the peaks of all threads to their current usage
+    pub fn reset_peaks(&self) {
+        for i in 0..self.len() {
+            if let Some(slot) = self.get(i) {
+                slot.live.reset_peaks();
+            }
+        }
+        for exited in self.lock_exited_threads().values_mut() {
+            exited.counters.live.reset_peaks();
+        }
+    }
+
+    /// Account a reallocation by the current thread of a block allocated by the thread `owner_uid` in `owner_slot`.
+    /// A block reallocated by a thread other than its owner changes ownership to the current thread, which is returned.
+    /// Must be called with `IN_ALLOC` set.
+    pub fn realloc(
+        &self,
+        owner_slot: usize,
+        owner_uid: usize,
+        old_size: usize,
+        new_size: usize,
+        moved: bool,
+    ) -> ThreadRef {
+        let (current, slot) = self.current_slot();
+        slot.reallocs.fetch_add(1, Ordering::Relaxed);
+        slot.sizes.record_size(new_size);
+        if moved {
+            slot.realloc_moves.fetch_add(1, Ordering::Relaxed);
+        }
+        if new_size >= old_size {
+            slot.realloc_grown
+                .fetch_add(new_size - old_size, Ordering::Relaxed);
+        } else {
+            slot.realloc_shrunk
+                .fetch_add(old_size - new_size, Ordering::Relaxed);
+        }
+        if owner_uid != current.uid {
+            self.free(owner_slot, owner_uid, old_size, None);
+            slot.alloc.fetch_add(new_size, Ordering::Relaxed);
+            slot.live.add(new_size, 1);
+        } else if new_size >= old_size {
+            slot.alloc.fetch_add(new_size - old_size, Ordering::Relaxed);
+            slot.live.add(new_size - old_size, 0);
+        } else {
+            slot.did_free
+                .fetch_add(old_size - new_size, Ordering::Relaxed);
+            slot.self_freed
+                .fetch_add(old_size - new_size, Ordering::Relaxed);
+            slot.live.remove(old_size - new_size, 0);
+        }
+        current
+    }
+}
+
+/// The slot owned by a live thread, released when the thread exits
+pub(crate) struct ThreadHandle {
+    pub slot: usize,
+    pub uid: usize,
+}
+
+impl ThreadHandle {
+    fn acquire() -> Self {
+        let slot = FREE_SLOTS
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+            .pop()
+            .unwrap_or_else(|| NEXT_SLOT.fetch_add(1, Ordering::AcqRel));
+        let uid = THREAD_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+        ThreadHandle { slot, uid }
+    }
+}
+
+impl Drop for ThreadHandle {
+    fn drop(&mut self) {
+        enter_alloc(|| {
+            let name = crate::current_thread_name().unwrap_or_else(|| self.uid.to_string());
+            for_each_tracker(|tracker| tracker.threads.release(self, &name));
+            FREE_SLOTS
+                .lock()
+                .unwrap_or_else(|poison| poison.into_inner())
+                .push(self.slot);
+        });
+    }
+}
+
+thread_local! {
+    static THREAD_HANDLE: ThreadHandle = enter_alloc(ThreadHandle::acquire);
+}
+
+/// Slot and uid of the current thread, or `RETIRED_SLOT` if its thread locals have been destroyed.
+pub(crate) fn current_thread() -> ThreadRef {
+    THREAD_HANDLE
+        .try_with(|handle| ThreadRef {
+            slot: handle.slot,
+            uid: handle.uid,
+        })
+        .unwrap_or(ThreadRef {
+            slot: RETIRED_SLOT,
+            uid: RETIRED_UID,
+        })
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ThreadRef {
+    pub slot: usize,
+    pub uid: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode, Tracker, EXITED_THREADS_NAME};
+
+    fn tracked_alloc() -> (&'static Tracker, &'static AllocTrack<System>) {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker);
+        (tracker, Box::leak(Box::new(alloc)))
+    }
+
+    const LAYOUT: Layout = Layout::new::<[u64; 4]>();
+
+    /// Allocate a block on a new thread, returning the block and the slot and uid of the exited thread
+    fn alloc_on_thread(alloc: &'static AllocTrack<System>) -> (usize, ThreadRef) {
+        thread::spawn(|| (unsafe { alloc.alloc(LAYOUT) } as usize, current_thread()))
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    pub fn test_chunk_position() {
+        let mut expected = (0, 0);
+        for index in 0..100_000 {
+            assert_eq!(chunk_position(index), expected, "index {index}");
+            expected.1 += 1;
+            if expected.1 == FIRST_CHUNK_SLOTS << expected.0 {
+                expected = (expected.0 + 1, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_slot_recycling() {
+        let (tracker, alloc) = tracked_alloc();
+        let (first_ptr, first) = alloc_on_thread(alloc);
+        let mut slots = vec![first.slot];
+        let mut ptrs = vec![];
+        for _ in 0..63 {
+            let (ptr, thread) = alloc_on_thread(alloc);
+            slots.push(thread.slot);
+            ptrs.push(ptr);
+        }
+        slots.sort();
+        slots.dedup();
+        // other tests may take a released slot in between, but most are reused
+        assert!(slots.len() < 32, "{} slots for 64 threads", slots.len());
+
+        // the exited thread keeps its counters, whichever thread now owns its slot
+        let slot = tracker.threads.get(first.slot).unwrap();
+        assert_ne!(slot.uid.load(Ordering::Relaxed), first.uid);
+        unsafe { alloc.dealloc(first_ptr as *mut u8, LAYOUT) };
+        let exited_threads = tracker.threads.lock_exited_threads();
+        let counters = &exited_threads[&first.uid].counters;
+        assert_eq!(counters.alloc, LAYOUT.size());
+        assert_eq!(counters.live.allocations, 0);
+        assert_eq!(
+            counters.freed_by_others[&current_thread().uid],
+            LAYOUT.size()
+        );
+        drop(exited_threads);
+        for slot in slots {
+            if let Some(slot) = tracker.threads.get(slot) {
+                assert_eq!(slot.counters().alloc, 0);
+            }
+        }
+        for ptr in ptrs {
+            unsafe { alloc.dealloc(ptr as *mut u8, LAYOUT) };
+        }
+        assert_eq!(tracker.global_stats().live_bytes, 0);
+    }
+
+    #[test]
+    fn test_retired_slot() {
+        let (tracker, alloc) = tracked_alloc();
+        tracker.set_exited_thread_retention(1);
+        let exited = (0..3).map(|_| alloc_on_thread(alloc)).collect::<Vec<_>>();
+        let exited_threads = tracker.threads.lock_exited_threads();
+        assert_eq!(
+            exited_threads.keys().collect::<Vec<_>>(),
+            [&exited[2].1.uid]
+        );
+        drop(exited_threads);
+        let retired = tracker.threads.get(RETIRED_SLOT).unwrap().counters();
+        assert_eq!(retired.alloc, 2 * LAYOUT.size());
+        assert_eq!(retired.live.allocations, 2);
+
+        for (ptr, _) in &exited {
+            unsafe { alloc.dealloc(*ptr as *mut u8, LAYOUT) };
+        }
+        let retired = tracker.threads.get(RETIRED_SLOT).unwrap().counters();
+        assert_eq!(retired.live.allocations, 0);
+        assert_eq!(
+            retired.freed_by_others[&current_thread().uid],
+            2 * LAYOUT.size()
+        );
+        let report = tracker.thread_report();
+        let metric = &report.0[EXITED_THREADS_NAME];
+        assert_eq!(metric.total_alloc, 2 * LAYOUT.size() as u64);
+        assert_eq!(metric.current_used, 0);
+
+        // folding the last retained thread as well
+        tracker.set_exited_thread_retention(0);
+        assert!(tracker.threads.lock_exited_threads().is_empty());
+        let retired = tracker.threads.get(RETIRED_SLOT).unwrap().counters();
+        assert_eq!(retired.alloc, 3 * LAYOUT.size());
+        assert_eq!(retired.live.allocations, 0);
+    }
+
+    #[test]
+    fn test_concurrent_exit_and_free() {
+        // threads exit and get retired while their blocks are freed by another thread and reports are taken,
+        // which takes the freed_by_others, exited_threads and retired locks in all combinations
+        let (tracker, alloc) = tracked_alloc();
+        tracker.set_exited_thread_retention(2);
+        let (sender, receiver) = mpsc::channel::<usize>();
+        let freeing = thread::spawn(move || {
+            for ptr in receiver {
+                unsafe { alloc.dealloc(ptr as *mut u8, LAYOUT) };
+            }
+        });
+        let allocating = (0..4)
+            .map(|_| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let sender = sender.clone();
+                        thread::spawn(move || {
+                            for _ in 0..20 {
+                                sender
+                                    .send(unsafe { alloc.alloc(LAYOUT) } as usize)
+                                    .unwrap();
+                            }
+                        })
+                        .join()
+                        .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(sender);
+        for _ in 0..20 {
+            tracker.thread_report();
+            tracker.set_exited_thread_retention(1);
+            tracker.set_exited_thread_retention(2);
+        }
+        for thread in allocating {
+            thread.join().unwrap();
+        }
+        freeing.join().unwrap();
+
+        let stats = tracker.global_stats();
+        assert_eq!(stats.total_allocated, 4000 * LAYOUT.size() as u64);
+        assert_eq!(stats.live_bytes, 0);
+        let report = tracker.thread_report();
+        let allocated: u64 = report.0.values().map(|x| x.total_alloc).sum();
+        let used: u64 = report.0.values().map(|x| x.current_used).sum();
+        assert_eq!((allocated, used), (4000 * LAYOUT.size() as u64, 0));
+    }
+}
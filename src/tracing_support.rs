@@ -0,0 +1,219 @@
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    fmt,
+    ptr::null,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tracing::{span, Level, Subscriber};
+use tracing_subscriber::{
+    layer::Context,
+    registry::{LookupSpan, SpanRef},
+    Layer,
+};
+
+use crate::{
+    enter_alloc,
+    tags::{TagMetric, NO_TAG, TAG_NAMES},
+    Tracker, GLOBAL_TRACKER,
+};
+
+thread_local! {
+    /// `TAG_NAMES` id of the path of the span currently entered on this thread, `NO_TAG` if none
+    static CURRENT_SPAN: Cell<usize> = const { Cell::new(NO_TAG) };
+    /// Counters of the span instance currently entered on this thread, kept alive by the span's extensions
+    static CURRENT_SPAN_COUNTERS: Cell<*const SpanCounters> = const { Cell::new(null()) };
+}
+
+/// `TAG_NAMES` id of the span path currently entered on this thread, to be stored with an allocation
+pub(crate) fn current_span() -> usize {
+    CURRENT_SPAN.with(|x| x.get())
+}
+
+/// Account `size` bytes allocated (or grown by reallocation) to the span instance entered on this thread
+pub(crate) fn span_instance_alloc(size: usize, allocations: usize) {
+    let counters = CURRENT_SPAN_COUNTERS.with(|x| x.get());
+    if let Some(counters) = unsafe { counters.as_ref() } {
+        counters.allocated.fetch_add(size, Ordering::Relaxed);
+        counters
+            .allocations
+            .fetch_add(allocations, Ordering::Relaxed);
+    }
+}
+
+/// Account `size` bytes freed (or shrunk by reallocation) to the span instance entered on this thread
+pub(crate) fn span_instance_free(size: usize) {
+    let counters = CURRENT_SPAN_COUNTERS.with(|x| x.get());
+    if let Some(counters) = unsafe { counters.as_ref() } {
+        counters.freed.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+/// Allocations and frees made while a single span instance was entered
+#[derive(Default)]
+struct SpanCounters {
+    allocated: AtomicUsize,
+    allocations: AtomicUsize,
+    freed: AtomicUsize,
+}
+
+/// Stored in the extensions of every span
+struct SpanData {
+    id: usize,
+    counters: Arc<SpanCounters>,
+}
+
+/// A `tracing_subscriber` layer attributing allocations made while a span is entered to that span, by target and name.
+/// Nested spans are reported as paths, i.e. `my_crate::server::request/my_crate::db::query`, and also count towards their parents.
+/// See `span_report`.
+///
+/// When a span closes, an event with target `alloc_track` is emitted with the bytes allocated and freed while that span was entered,
+/// unless disabled with `with_close_events`. These per-instance totals count the allocations of all trackers.
+pub struct AllocTrackLayer {
+    close_events: bool,
+}
+
+impl AllocTrackLayer {
+    pub fn new() -> Self {
+        Self { close_events: true }
+    }
+
+    /// Whether to emit an event with allocation totals when a span closes, defaults to true
+    pub fn with_close_events(mut self, close_events: bool) -> Self {
+        self.close_events = close_events;
+        self
+    }
+}
+
+impl Default for AllocTrackLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn set_current_span<'a, S: LookupSpan<'a>>(span: Option<SpanRef<'a, S>>) {
+    let (id, counters) = match span.as_ref().and_then(|span| {
+        span.extensions()
+            .get::<SpanData>()
+            .map(|data| (data.id, Arc::as_ptr(&data.counters)))
+    }) {
+        Some(current) => current,
+        None => (NO_TAG, null()),
+    };
+    CURRENT_SPAN.with(|x| x.set(id));
+    CURRENT_SPAN_COUNTERS.with(|x| x.set(counters));
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for AllocTrackLayer {
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<SpanData>().map(|data| data.id))
+            .unwrap_or(NO_TAG);
+        let metadata = span.metadata();
+        let data = SpanData {
+            id: TAG_NAMES.id(parent, metadata.target(), metadata.name()),
+            counters: Arc::default(),
+        };
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_enter(&self, _id: &span::Id, ctx: Context<'_, S>) {
+        set_current_span(ctx.lookup_current());
+    }
+
+    fn on_exit(&self, _id: &span::Id, ctx: Context<'_, S>) {
+        set_current_span(ctx.lookup_current());
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if !self.close_events {
+            return;
+        }
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(counters) = span
+            .extensions()
+            .get::<SpanData>()
+            .map(|data| data.counters.clone())
+        else {
+            return;
+        };
+        let metadata = span.metadata();
+        tracing::event!(
+            target: "alloc_track",
+            Level::INFO,
+            span.target = metadata.target(),
+            span.name = metadata.name(),
+            allocated = counters.allocated.load(Ordering::Relaxed),
+            allocations = counters.allocations.load(Ordering::Relaxed),
+            freed = counters.freed.load(Ordering::Relaxed),
+            "span closed"
+        );
+    }
+}
+
+/// A report of all spans by path, see `AllocTrackLayer`
+#[derive(Clone)]
+pub struct SpanReport(pub BTreeMap<String, TagMetric>);
+
+impl fmt::Display for SpanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (path, metric) in &self.0 {
+            writeln!(f, "{path}:\n{metric}\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Generate a report of allocations by span of the global tracker, see `Tracker::span_report`
+pub fn span_report() -> SpanReport {
+    GLOBAL_TRACKER.span_report()
+}
+
+impl Tracker {
+    /// Generate a report of allocations by span, see `AllocTrackLayer`.
+    /// Note that the numbers are not a synchronized snapshot, and have slight timing skew.
+    pub fn span_report(&'static self) -> SpanReport {
+        SpanReport(enter_alloc(|| self.maps()).spans.metrics())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode};
+
+    #[test]
+    fn test_span_attribution() {
+        let alloc = AllocTrack::new(System, BacktraceMode::None);
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let subscriber = tracing_subscriber::registry().with(AllocTrackLayer::new());
+        let ptr = tracing::subscriber::with_default(subscriber, || {
+            let _outer = tracing::info_span!("test_outer").entered();
+            let _inner = tracing::info_span!("test_inner").entered();
+            unsafe { alloc.alloc(layout) }
+        });
+        assert_eq!(current_span(), NO_TAG);
+
+        let report = span_report();
+        let target = module_path!();
+        let outer = &report.0[&format!("{target}::test_outer")];
+        assert_eq!(outer.in_use(), 40);
+        let inner = &report.0[&format!("{target}::test_outer/{target}::test_inner")];
+        assert_eq!(inner.allocations, 1);
+        unsafe { alloc.dealloc(ptr, layout) };
+    }
+}
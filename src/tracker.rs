@@ -0,0 +1,654 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+};
+
+use dashmap::DashMap;
+
+#[cfg(feature = "backtrace")]
+use crate::backtrace_support::TraceInfo;
+use crate::{
+    budget::Budgets, enter_alloc, histogram::AtomicHistogram, tags::TagCounters,
+    thread_store::ThreadStoreTable, AllocFailure, BacktraceMode, GlobalStats, LiveCounters,
+    PeakMetric, PointerData, SizeHistogram, SIZE_BUCKETS,
+};
+use crate::{recording::Recording, watch::Watchers};
+
+/// Value of `Tracker::backtrace_mode` when not overridden
+const BACKTRACE_MODE_UNSET: u8 = u8::MAX;
+
+/// The tracker used by `AllocTrack` unless another one is set with `AllocTrack::with_tracker`,
+/// and by the free report functions such as `thread_report`.
+pub static GLOBAL_TRACKER: Tracker = Tracker::new();
+
+/// All trackers that have tracked an allocation, so that exiting threads can be released from each of them
+static TRACKERS: Mutex<Vec<&'static Tracker>> = Mutex::new(Vec::new());
+
+/// Call `func` on every tracker that has tracked an allocation. Must be called with `IN_ALLOC` set.
+pub(crate) fn for_each_tracker(func: impl Fn(&'static Tracker)) {
+    for tracker in TRACKERS
+        .lock()
+        .unwrap_or_else(|poison| poison.into_inner())
+        .iter()
+    {
+        func(tracker);
+    }
+}
+
+/// Tracking state that can not be created in a `const` context
+pub(crate) struct TrackerMaps {
+    /// pointer -> data
+    pub ptr_map: DashMap<usize, PointerData>,
+    /// backtrace -> current allocation size
+    #[cfg(feature = "backtrace")]
+    pub trace_map: DashMap<u64, TraceInfo>,
+    pub tags: TagCounters,
+    pub tasks: TagCounters,
+    #[cfg(feature = "tracing")]
+    pub spans: TagCounters,
+}
+
+/// The books kept by one or more `AllocTrack` allocators: live pointers, per-thread and per-backtrace counters, and so on.
+/// Every report is a method on the tracker it covers, so that allocators wrapping unrelated allocators,
+/// i.e. the global allocator and a custom arena, can be reported on separately.
+///
+/// A tracker must be `'static`, as it is shared with thread exit handlers. Use a `static`, or leak a boxed tracker.
+/// Thread ids and tag, task and span names are shared by all trackers.
+pub struct Tracker {
+    maps: OnceLock<TrackerMaps>,
+    /// Live allocations
+    pub(crate) live: LiveCounters,
+    /// Total bytes allocated
+    pub(crate) total_allocated: AtomicUsize,
+    /// Total bytes freed
+    pub(crate) total_freed: AtomicUsize,
+    /// Sizes of allocations and reallocations
+    pub(crate) sizes: AtomicHistogram<SIZE_BUCKETS>,
+    pub(crate) threads: ThreadStoreTable,
+    /// Runtime override of `AllocTrack::backtrace`, `BACKTRACE_MODE_UNSET` if not overridden
+    backtrace_mode: AtomicU8,
+    /// When set, `AllocTrack` passes allocations straight through to the inner allocator
+    paused: AtomicBool,
+    /// Untracked blocks not freed yet, allocated while paused or inside the allocator, i.e. by observers.
+    /// Tells their frees apart from double frees.
+    untracked_blocks: AtomicUsize,
+    oom_hook: RwLock<Option<fn(&AllocFailure)>>,
+    pub(crate) budgets: RwLock<Budgets>,
+    /// Whether `budgets` is not empty, to skip checking budgets without taking the lock
+    pub(crate) has_budgets: AtomicBool,
+    pub(crate) watchers: RwLock<Watchers>,
+    /// Whether `watchers` is not empty, to skip checking watchers without taking the lock
+    pub(crate) has_watchers: AtomicBool,
+    pub(crate) recording: Mutex<Option<Arc<Recording>>>,
+    /// Id of `recording`, 0 if not recording, to skip recording without taking the lock
+    pub(crate) recording_id: AtomicU64,
+}
+
+impl Tracker {
+    pub const fn new() -> Self {
+        Self {
+            maps: OnceLock::new(),
+            live: LiveCounters::new(),
+            total_allocated: AtomicUsize::new(0),
+            total_freed: AtomicUsize::new(0),
+            sizes: AtomicHistogram::new(),
+            threads: ThreadStoreTable::new(),
+            backtrace_mode: AtomicU8::new(BACKTRACE_MODE_UNSET),
+            paused: AtomicBool::new(false),
+            untracked_blocks: AtomicUsize::new(0),
+            oom_hook: RwLock::new(None),
+            budgets: RwLock::new(Budgets::new()),
+            has_budgets: AtomicBool::new(false),
+            watchers: RwLock::new(Watchers::new()),
+            has_watchers: AtomicBool::new(false),
+            recording: Mutex::new(None),
+            recording_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Maps of this tracker, registering the tracker on first use. Must be called with `IN_ALLOC` set.
+    pub(crate) fn maps(&'static self) -> &'static TrackerMaps {
+        self.maps.get_or_init(|| {
+            TRACKERS
+                .lock()
+                .unwrap_or_else(|poison| poison.into_inner())
+                .push(self);
+            TrackerMaps {
+                ptr_map: DashMap::new(),
+                #[cfg(feature = "backtrace")]
+                trace_map: DashMap::new(),
+                tags: TagCounters::new(),
+                tasks: TagCounters::new(),
+                #[cfg(feature = "tracing")]
+                spans: TagCounters::new(),
+            }
+        })
+    }
+
+    /// Peak of tracked live memory
+    pub fn process_peak(&self) -> PeakMetric {
+        self.live.load().peak()
+    }
+
+    /// Get allocation counters. These are maintained in the allocation path,
+    /// so this is cheap enough to poll frequently, unlike `thread_report`.
+    /// Note that the numbers are not a synchronized snapshot, and have slight timing skew.
+    pub fn global_stats(&self) -> GlobalStats {
+        let live = self.live.load();
+        GlobalStats {
+            total_allocated: self.total_allocated.load(Ordering::Relaxed) as u64,
+            total_freed: self.total_freed.load(Ordering::Relaxed) as u64,
+            live_bytes: live.bytes as u64,
+            live_allocations: live.allocations as u64,
+            peak: live.peak(),
+        }
+    }
+
+    /// Histogram of the sizes of all allocations and reallocations.
+    /// Like `global_stats`, this does not walk any per-thread or per-backtrace state.
+    pub fn size_histogram(&self) -> SizeHistogram {
+        self.sizes.load_sizes()
+    }
+
+    /// Reset all tracker-wide, per-thread and per-backtrace peaks to current usage, to measure the peak of a particular phase.
+    pub fn reset_peaks(&'static self) {
+        enter_alloc(|| {
+            let maps = self.maps();
+            self.live.reset_peaks();
+            self.threads.reset_peaks();
+            maps.tags.reset_peaks();
+            maps.tasks.reset_peaks();
+            #[cfg(feature = "tracing")]
+            maps.spans.reset_peaks();
+            #[cfg(feature = "backtrace")]
+            for mut entry in maps.trace_map.iter_mut() {
+                entry.reset_peak();
+            }
+        });
+    }
+
+    /// Override the `BacktraceMode` passed to `AllocTrack::new` at runtime, for all allocators using this tracker.
+    /// Switching to `BacktraceMode::None` goes back to thread-only accounting, existing backtrace metrics are kept.
+    pub fn set_backtrace_mode(&self, mode: BacktraceMode) {
+        self.backtrace_mode.store(mode as u8, Ordering::Relaxed);
+    }
+
+    /// Remove any override set by `set_backtrace_mode`, going back to the mode passed to `AllocTrack::new`.
+    pub fn reset_backtrace_mode(&self) {
+        self.backtrace_mode
+            .store(BACKTRACE_MODE_UNSET, Ordering::Relaxed);
+    }
+
+    /// The override set by `set_backtrace_mode`, if any
+    pub(crate) fn backtrace_mode_override(&self) -> Option<BacktraceMode> {
+        BacktraceMode::from_u8(self.backtrace_mode.load(Ordering::Relaxed))
+    }
+
+    /// Pause tracking. New allocations are passed straight through to the inner allocator and are never tracked.
+    /// Allocations tracked before pausing are still accounted for when freed.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resume tracking after a call to `pause`.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns true if tracking is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Count a block passed through untracked, while paused or inside the allocator
+    pub(crate) fn add_untracked_block(&self) {
+        self.untracked_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account the free of an untracked block inside the allocator
+    pub(crate) fn remove_untracked_block(&self) {
+        self.untracked_blocks
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |blocks| {
+                blocks.checked_sub(1)
+            })
+            .ok();
+    }
+
+    /// Account the free of a block missing from the books, which must have been allocated untracked.
+    /// Anything else is a double free, which aborts in debug builds as the allocator must not unwind.
+    pub(crate) fn free_untracked_block(&self, ptr: *mut u8) {
+        let untracked_block = self
+            .untracked_blocks
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |blocks| {
+                blocks.checked_sub(1)
+            })
+            .is_ok();
+        if cfg!(debug_assertions) && !untracked_block {
+            eprintln!("alloc-track: double free of {ptr:p}");
+            std::process::abort();
+        }
+    }
+
+    /// Register a hook called whenever the inner allocator fails to allocate, replacing any previous hook.
+    /// The hook runs inside the allocator: its own allocations are not tracked, and it must not panic.
+    pub fn set_oom_hook(&self, hook: fn(&AllocFailure)) {
+        *self
+            .oom_hook
+            .write()
+            .unwrap_or_else(|poison| poison.into_inner()) = Some(hook);
+    }
+
+    /// Unregister the hook registered with `set_oom_hook`, if any.
+    pub fn clear_oom_hook(&self) {
+        *self
+            .oom_hook
+            .write()
+            .unwrap_or_else(|poison| poison.into_inner()) = None;
+    }
+
+    pub(crate) fn oom_hook(&self) -> Option<fn(&AllocFailure)> {
+        *self
+            .oom_hook
+            .read()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    /// Set how many exited threads `thread_report` lists individually, 256 by default.
+    /// Beyond this, the oldest exited threads are folded into a single entry named `EXITED_THREADS_NAME`.
+    pub fn set_exited_thread_retention(&self, limit: usize) {
+        enter_alloc(|| self.threads.set_exited_thread_retention(limit));
+    }
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Tracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracker")
+            .field("global_stats", &self.global_stats())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        ptr::null_mut,
+    };
+
+    use super::*;
+    use crate::{
+        exited_thread_name, thread_store::current_thread, AllocEvent, AllocFailure, AllocObserver,
+        AllocTrack, GlobalStats, PeakMetric, EXITED_THREADS_NAME,
+    };
+
+    /// `System`, except that allocations and reallocations fail while the flag is set
+    pub(crate) struct FailingAlloc(pub(crate) &'static AtomicBool);
+
+    unsafe impl GlobalAlloc for FailingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if self.0.load(Ordering::Relaxed) {
+                return null_mut();
+            }
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            if self.0.load(Ordering::Relaxed) {
+                return null_mut();
+            }
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[test]
+    fn test_separate_trackers() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker);
+        let other_tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let other = AllocTrack::new(System, BacktraceMode::None).with_tracker(other_tracker);
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+        let other_ptr = unsafe { other.alloc(Layout::from_size_align(16, 8).unwrap()) };
+
+        assert_eq!(tracker.global_stats().live_bytes, 128);
+        assert_eq!(other_tracker.global_stats().live_bytes, 16);
+        let live = tracker.live_allocations();
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].address, ptr as usize);
+        let used: u64 = tracker
+            .thread_report()
+            .0
+            .values()
+            .map(|x| x.current_used)
+            .sum();
+        assert_eq!(used, 128);
+
+        unsafe { alloc.dealloc(ptr, layout) };
+        assert_eq!(tracker.global_stats().live_bytes, 0);
+        assert_eq!(tracker.global_stats().peak.bytes, 128);
+        assert_eq!(other_tracker.global_stats().live_bytes, 16);
+        unsafe { other.dealloc(other_ptr, Layout::from_size_align(16, 8).unwrap()) };
+    }
+
+    #[test]
+    #[cfg(feature = "backtrace")]
+    fn test_switch_backtrace_mode() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let before = unsafe { alloc.alloc(layout) };
+        tracker.set_backtrace_mode(BacktraceMode::Short);
+        assert_eq!(alloc.backtrace_mode(), BacktraceMode::Short);
+        let traced = unsafe { alloc.alloc(layout) };
+        tracker.set_backtrace_mode(BacktraceMode::None);
+        let after = unsafe { alloc.alloc(layout) };
+        // only the allocation made in between has a backtrace
+        let report = tracker.backtrace_report(|_, _| true);
+        assert_eq!(report.0.len(), 1);
+        assert_eq!(report.0[0].1.allocated, 64);
+        tracker.reset_backtrace_mode();
+        assert_eq!(alloc.backtrace_mode(), BacktraceMode::None);
+
+        for ptr in [before, traced, after] {
+            unsafe { alloc.dealloc(ptr, layout) };
+        }
+        assert_eq!(tracker.global_stats().live_bytes, 0);
+        assert_eq!(tracker.backtrace_report(|_, _| true).0[0].1.freed, 64);
+    }
+
+    #[test]
+    fn test_pause() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let tracked = unsafe { alloc.alloc(layout) };
+        tracker.pause();
+        assert!(tracker.is_paused());
+        let untracked = unsafe { alloc.alloc(layout) };
+        let untracked = unsafe { alloc.realloc(untracked, layout, 128) };
+        // frees of tracked allocations are still accounted while paused
+        unsafe { alloc.dealloc(tracked, layout) };
+        let other = unsafe { alloc.alloc(layout) };
+        tracker.resume();
+        assert!(!tracker.is_paused());
+        let stats = tracker.global_stats();
+        assert_eq!((stats.total_allocated, stats.total_freed), (64, 64));
+        assert_eq!(stats.live_allocations, 0);
+
+        // allocated while paused, so not accounted when reallocated or freed afterwards
+        let untracked =
+            unsafe { alloc.realloc(untracked, Layout::from_size_align(128, 8).unwrap(), 256) };
+        unsafe { alloc.dealloc(untracked, Layout::from_size_align(256, 8).unwrap()) };
+        unsafe { alloc.dealloc(other, layout) };
+        assert_eq!(tracker.global_stats().total_freed, 64);
+        assert_eq!(tracker.untracked_blocks.load(Ordering::Relaxed), 0);
+    }
+
+    /// Allocate `layout` on a new thread named `name`, returning the block and the uid of the exited thread
+    fn alloc_on_thread(
+        alloc: &'static AllocTrack<System>,
+        name: &str,
+        layout: Layout,
+    ) -> (usize, usize) {
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                (
+                    unsafe { alloc.alloc(layout) } as usize,
+                    current_thread().uid,
+                )
+            })
+            .unwrap()
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_exited_thread_retention() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc: &'static AllocTrack<System> = Box::leak(Box::new(
+            AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker),
+        ));
+        tracker.set_exited_thread_retention(2);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let exited_name = |name: &str, uid: usize| {
+            let name = if cfg!(feature = "fs") {
+                name.to_string()
+            } else {
+                uid.to_string()
+            };
+            exited_thread_name(&name, uid)
+        };
+        let (first_ptr, first) = alloc_on_thread(alloc, "worker-a", layout);
+        let (second_ptr, second) = alloc_on_thread(alloc, "worker-b", layout);
+        let report = tracker.thread_report();
+        for (name, uid) in [("worker-a", first), ("worker-b", second)] {
+            let metric = &report.0[&exited_name(name, uid)];
+            assert!(metric.exited_at.is_some());
+            assert_eq!(metric.current_used, 64);
+        }
+
+        // freeing memory of an exited thread keeps it listed
+        unsafe { alloc.dealloc(first_ptr as *mut u8, layout) };
+        let report = tracker.thread_report();
+        let metric = &report.0[&exited_name("worker-a", first)];
+        assert_eq!((metric.total_alloc, metric.current_used), (64, 0));
+        assert!(!report.0.contains_key(EXITED_THREADS_NAME));
+
+        // past the retention, the oldest exited thread is folded into a single entry
+        let (third_ptr, third) = alloc_on_thread(alloc, "worker-c", layout);
+        let report = tracker.thread_report();
+        assert!(!report.0.contains_key(&exited_name("worker-a", first)));
+        let folded = &report.0[EXITED_THREADS_NAME];
+        assert_eq!((folded.total_alloc, folded.current_used), (64, 0));
+        assert_eq!(report.0[&exited_name("worker-b", second)].current_used, 64);
+        assert_eq!(report.0[&exited_name("worker-c", third)].current_used, 64);
+
+        for ptr in [second_ptr, third_ptr] {
+            unsafe { alloc.dealloc(ptr as *mut u8, layout) };
+        }
+        let used: u64 = tracker
+            .thread_report()
+            .0
+            .values()
+            .map(|x| x.current_used)
+            .sum();
+        assert_eq!(used, 0);
+    }
+
+    #[test]
+    fn test_failed_allocs() {
+        static FAIL: AtomicBool = AtomicBool::new(false);
+        /// Size, whether it was a reallocation, and thread of failures passed to `record_failure`
+        static FAILURES: Mutex<Vec<(usize, bool, usize)>> = Mutex::new(Vec::new());
+
+        fn record_failure(failure: &AllocFailure) {
+            FAILURES.lock().unwrap().push((
+                failure.layout.size(),
+                failure.realloc,
+                failure.thread_id,
+            ));
+        }
+
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(FailingAlloc(&FAIL), BacktraceMode::None).with_tracker(tracker);
+        tracker.set_oom_hook(record_failure);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+
+        FAIL.store(true, Ordering::Relaxed);
+        assert!(unsafe { alloc.alloc(Layout::from_size_align(100, 8).unwrap()) }.is_null());
+        assert!(unsafe { alloc.realloc(ptr, layout, 200) }.is_null());
+        FAIL.store(false, Ordering::Relaxed);
+
+        // failures are counted, but never as live memory
+        let stats = tracker.global_stats();
+        assert_eq!((stats.total_allocated, stats.live_bytes), (64, 64));
+        assert_eq!(stats.live_allocations, 1);
+        let live = tracker.live_allocations();
+        assert_eq!((live.len(), live[0].address), (1, ptr as usize));
+        let report = tracker.thread_report();
+        let failed_allocs: u64 = report.0.values().map(|x| x.failed_allocs).sum();
+        let failed_bytes: u64 = report.0.values().map(|x| x.failed_bytes).sum();
+        assert_eq!((failed_allocs, failed_bytes), (2, 300));
+        let uid = current_thread().uid;
+        assert_eq!(
+            *FAILURES.lock().unwrap(),
+            [(100, false, uid), (200, true, uid)]
+        );
+
+        unsafe { alloc.dealloc(ptr, layout) };
+        assert_eq!(tracker.global_stats().live_bytes, 0);
+    }
+
+    #[test]
+    fn test_reset_peaks() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        #[cfg(feature = "backtrace")]
+        let mode = BacktraceMode::Short;
+        #[cfg(not(feature = "backtrace"))]
+        let mode = BacktraceMode::None;
+        let alloc = AllocTrack::new(System, mode).with_tracker(tracker);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptrs = [(); 3].map(|_| unsafe { alloc.alloc(layout) });
+        for ptr in &ptrs[1..] {
+            unsafe { alloc.dealloc(*ptr, layout) };
+        }
+        let thread_peak = || {
+            let report = tracker.thread_report();
+            assert_eq!(report.0.len(), 1);
+            report.0.into_values().next().unwrap().peak
+        };
+        let peak = PeakMetric {
+            bytes: 300,
+            allocations: 3,
+        };
+        assert_eq!(tracker.global_stats().peak, peak);
+        assert_eq!(tracker.process_peak(), peak);
+        assert_eq!(thread_peak(), peak);
+        #[cfg(feature = "backtrace")]
+        assert_eq!(tracker.backtrace_report(|_, _| true).0[0].1.peak, peak);
+
+        // peaks restart from current usage
+        tracker.reset_peaks();
+        let peak = PeakMetric {
+            bytes: 100,
+            allocations: 1,
+        };
+        assert_eq!(tracker.global_stats().peak, peak);
+        assert_eq!(thread_peak(), peak);
+        #[cfg(feature = "backtrace")]
+        assert_eq!(tracker.backtrace_report(|_, _| true).0[0].1.peak, peak);
+
+        let ptr = unsafe { alloc.alloc(layout) };
+        let peak = PeakMetric {
+            bytes: 200,
+            allocations: 2,
+        };
+        assert_eq!(tracker.global_stats().peak, peak);
+        assert_eq!(thread_peak(), peak);
+        for ptr in [ptrs[0], ptr] {
+            unsafe { alloc.dealloc(ptr, layout) };
+        }
+        assert_eq!(tracker.global_stats().peak, peak);
+    }
+
+    #[test]
+    fn test_global_stats() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc: &'static AllocTrack<System> = Box::leak(Box::new(
+            AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker),
+        ));
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+        let ptr = unsafe { alloc.realloc(ptr, layout, 250) };
+        let ptr = unsafe { alloc.realloc(ptr, Layout::from_size_align(250, 8).unwrap(), 50) };
+        assert_eq!(
+            tracker.global_stats(),
+            GlobalStats {
+                total_allocated: 250,
+                total_freed: 200,
+                live_bytes: 50,
+                live_allocations: 1,
+                peak: PeakMetric {
+                    bytes: 250,
+                    allocations: 1,
+                },
+            }
+        );
+
+        // counted across threads without walking them
+        let threads = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        unsafe { alloc.dealloc(alloc.alloc(layout), layout) };
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let stats = tracker.global_stats();
+        assert_eq!(stats.total_allocated, 250 + 4000 * 100);
+        assert_eq!(stats.total_freed, 200 + 4000 * 100);
+        assert_eq!((stats.live_bytes, stats.live_allocations), (50, 1));
+        assert!(stats.peak.bytes >= 250 && stats.peak.bytes <= 50 + 4 * 100);
+
+        unsafe { alloc.dealloc(ptr, Layout::from_size_align(50, 8).unwrap()) };
+        let stats = tracker.global_stats();
+        assert_eq!((stats.live_bytes, stats.live_allocations), (0, 0));
+        assert_eq!(stats.total_allocated - stats.total_freed, 0);
+    }
+
+    struct FreeCounter(AtomicUsize);
+
+    impl AllocObserver for FreeCounter {
+        fn on_dealloc(&self, _event: &AllocEvent) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_paused_realloc() {
+        static FREES: FreeCounter = FreeCounter(AtomicUsize::new(0));
+        static FAIL: AtomicBool = AtomicBool::new(false);
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(FailingAlloc(&FAIL), BacktraceMode::None)
+            .with_tracker(tracker)
+            .with_observer(&FREES);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+        tracker.pause();
+        FAIL.store(true, Ordering::Relaxed);
+        assert!(unsafe { alloc.realloc(ptr, layout, 128) }.is_null());
+        // still tracked, as it was not reallocated
+        let stats = tracker.global_stats();
+        assert_eq!((stats.live_bytes, stats.live_allocations), (64, 1));
+        assert_eq!(FREES.0.load(Ordering::Relaxed), 0);
+
+        FAIL.store(false, Ordering::Relaxed);
+        let new_ptr = unsafe { alloc.realloc(ptr, layout, 128) };
+        assert!(!new_ptr.is_null());
+        let stats = tracker.global_stats();
+        assert_eq!((stats.live_bytes, stats.total_freed), (0, 64));
+        assert_eq!(FREES.0.load(Ordering::Relaxed), 1);
+        tracker.resume();
+        unsafe { alloc.dealloc(new_ptr, Layout::from_size_align(128, 8).unwrap()) };
+        assert_eq!(FREES.0.load(Ordering::Relaxed), 1);
+    }
+}
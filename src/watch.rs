@@ -0,0 +1,429 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+#[cfg(feature = "backtrace")]
+use crate::HashedBacktrace;
+use crate::{enter_alloc, Tracker, GLOBAL_TRACKER};
+
+/// When a watcher fires, see `Tracker::watch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchCondition {
+    /// Live bytes of the tracker rise above this many bytes
+    LiveBytes(u64),
+    /// The bytes in use by a single backtrace rise above this many bytes, as estimated in `backtrace_report`
+    #[cfg(feature = "backtrace")]
+    BacktraceInUse(u64),
+}
+
+impl WatchCondition {
+    fn threshold(&self) -> u64 {
+        match self {
+            WatchCondition::LiveBytes(threshold) => *threshold,
+            #[cfg(feature = "backtrace")]
+            WatchCondition::BacktraceInUse(threshold) => *threshold,
+        }
+    }
+}
+
+/// A crossing of a watched threshold, passed to the callback of `Tracker::watch`
+pub struct WatchEvent {
+    pub condition: WatchCondition,
+    /// Live bytes of the tracker, or in-use bytes of the backtrace, right after the crossing
+    pub bytes: u64,
+    /// Hash of the backtrace that crossed a `WatchCondition::BacktraceInUse` threshold
+    #[cfg(feature = "backtrace")]
+    pub trace_hash: Option<u64>,
+    tracker: &'static Tracker,
+}
+
+impl WatchEvent {
+    /// The tracker the threshold was crossed in
+    pub fn tracker(&self) -> &'static Tracker {
+        self.tracker
+    }
+
+    /// Look up and symbolize the backtrace that crossed a `WatchCondition::BacktraceInUse` threshold
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace(&self) -> Option<HashedBacktrace> {
+        self.tracker.resolve_backtrace(self.trace_hash?)
+    }
+}
+
+/// Handle to remove a watcher with `Tracker::unwatch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatcherId(usize);
+
+type WatchCallback = Box<dyn Fn(&WatchEvent) + Send + Sync>;
+
+pub(crate) struct Watcher {
+    id: usize,
+    condition: WatchCondition,
+    /// Usage must drop below `threshold - hysteresis` before the watcher fires again
+    rearm_below: u64,
+    /// Whether a `WatchCondition::LiveBytes` watcher fires on its next crossing
+    armed: AtomicBool,
+    /// Backtraces a `WatchCondition::BacktraceInUse` watcher fired for, and that have not dropped below `rearm_below` since
+    #[cfg_attr(not(feature = "backtrace"), allow(dead_code))]
+    fired: Mutex<HashSet<u64>>,
+    /// Length of `fired`, to skip locking it when empty
+    #[cfg_attr(not(feature = "backtrace"), allow(dead_code))]
+    fired_len: AtomicUsize,
+    callback: WatchCallback,
+}
+
+impl Watcher {
+    #[cfg(feature = "backtrace")]
+    fn lock_fired(&self) -> std::sync::MutexGuard<'_, HashSet<u64>> {
+        self.fired
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
+
+/// The watchers of a tracker
+pub(crate) struct Watchers {
+    watchers: Vec<Arc<Watcher>>,
+    next_id: usize,
+}
+
+impl Watchers {
+    pub const fn new() -> Self {
+        Self {
+            watchers: Vec::new(),
+            next_id: 0,
+        }
+    }
+}
+
+struct PendingEvent {
+    watcher: Arc<Watcher>,
+    event: WatchEvent,
+}
+
+/// Crossings queued on a thread. The events hold watchers allocated outside of the allocator, the queue itself is allocated inside it.
+struct PendingEvents(Vec<PendingEvent>);
+
+impl Drop for PendingEvents {
+    fn drop(&mut self) {
+        self.0.clear();
+        enter_alloc(|| drop(std::mem::take(&mut self.0)));
+    }
+}
+
+thread_local! {
+    /// Crossings detected in the allocator on this thread, delivered once it leaves `IN_ALLOC`
+    static PENDING_EVENTS: RefCell<PendingEvents> = const { RefCell::new(PendingEvents(Vec::new())) };
+    /// Set while delivering events, so that allocations made by callbacks don't deliver recursively
+    static DELIVERING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Clears `DELIVERING` when delivery ends
+struct DeliveringGuard;
+
+impl Drop for DeliveringGuard {
+    fn drop(&mut self) {
+        DELIVERING.with(|x| x.set(false));
+    }
+}
+
+/// Queue `event` for delivery outside of the allocator. Must be called with `IN_ALLOC` set.
+fn queue_event(watcher: &Arc<Watcher>, event: WatchEvent) {
+    PENDING_EVENTS
+        .try_with(|pending| {
+            pending.borrow_mut().0.push(PendingEvent {
+                watcher: watcher.clone(),
+                event,
+            })
+        })
+        .ok();
+}
+
+/// Run the callbacks of all crossings queued on this thread. Must be called with `IN_ALLOC` unset.
+pub(crate) fn deliver_events() {
+    let has_pending = PENDING_EVENTS
+        .try_with(|pending| !pending.borrow().0.is_empty())
+        .unwrap_or(false);
+    if !has_pending || DELIVERING.with(|x| x.replace(true)) {
+        return;
+    }
+    let _delivering = DeliveringGuard;
+    while let Some(pending) = enter_alloc(|| PENDING_EVENTS.with(|x| x.borrow_mut().0.pop())) {
+        // called from `GlobalAlloc` methods, which must not unwind, so a panicking callback is cut short here
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            (pending.watcher.callback)(&pending.event)
+        }));
+        // dropped outside of the allocator, as it may hold the last reference to a removed watcher and its tracked callback
+    }
+}
+
+/// Register a watcher on the global tracker, see `Tracker::watch`
+pub fn watch(
+    condition: WatchCondition,
+    callback: impl Fn(&WatchEvent) + Send + Sync + 'static,
+) -> WatcherId {
+    GLOBAL_TRACKER.watch(condition, callback)
+}
+
+/// Remove a watcher from the global tracker, see `Tracker::unwatch`
+pub fn unwatch(id: WatcherId) {
+    GLOBAL_TRACKER.unwatch(id);
+}
+
+impl Tracker {
+    /// Call `callback` whenever `condition` starts to hold, i.e. when live memory crosses a threshold.
+    /// A watcher fires once per crossing: it fires again only after usage drops 10% below the threshold, see `watch_with_hysteresis`.
+    ///
+    /// Thresholds are checked in the allocation path, but `callback` runs on the allocating thread after the allocation is done,
+    /// outside of the allocator, so it may allocate, call `backtrace_report` or write a dump.
+    /// Crossings caused by allocations in `callback` itself are delivered after it returns.
+    ///
+    /// As `callback` runs on whichever thread crossed the threshold, from within `GlobalAlloc::alloc` or `dealloc`,
+    /// it must not panic and should not allocate heavily. A panic is caught and the event dropped,
+    /// as unwinding out of the allocator is undefined behavior.
+    pub fn watch(
+        &'static self,
+        condition: WatchCondition,
+        callback: impl Fn(&WatchEvent) + Send + Sync + 'static,
+    ) -> WatcherId {
+        self.watch_with_hysteresis(condition, condition.threshold() / 10, callback)
+    }
+
+    /// Like `watch`, but the watcher fires again only after usage drops `hysteresis` bytes below the threshold.
+    pub fn watch_with_hysteresis(
+        &'static self,
+        condition: WatchCondition,
+        hysteresis: u64,
+        callback: impl Fn(&WatchEvent) + Send + Sync + 'static,
+    ) -> WatcherId {
+        let callback: WatchCallback = Box::new(callback);
+        enter_alloc(|| {
+            let mut watchers = self
+                .watchers
+                .write()
+                .unwrap_or_else(|poison| poison.into_inner());
+            let id = watchers.next_id;
+            watchers.next_id += 1;
+            watchers.watchers.push(Arc::new(Watcher {
+                id,
+                condition,
+                rearm_below: condition.threshold().saturating_sub(hysteresis),
+                armed: AtomicBool::new(true),
+                fired: Mutex::new(HashSet::new()),
+                fired_len: AtomicUsize::new(0),
+                callback,
+            }));
+            self.has_watchers.store(true, Ordering::Release);
+            WatcherId(id)
+        })
+    }
+
+    /// Remove a watcher registered with `watch`. Crossings already detected may still be delivered.
+    pub fn unwatch(&self, id: WatcherId) {
+        let removed = enter_alloc(|| {
+            let mut watchers = self
+                .watchers
+                .write()
+                .unwrap_or_else(|poison| poison.into_inner());
+            let removed = watchers
+                .watchers
+                .iter()
+                .position(|watcher| watcher.id == id.0)
+                .map(|index| watchers.watchers.remove(index));
+            self.has_watchers
+                .store(!watchers.watchers.is_empty(), Ordering::Release);
+            removed
+        });
+        // the callback was allocated outside of the allocator
+        drop(removed);
+    }
+
+    /// Call `func` on every watcher. Must be called with `IN_ALLOC` set.
+    fn for_each_watcher(&'static self, func: impl Fn(&Arc<Watcher>)) {
+        if !self.has_watchers.load(Ordering::Acquire) {
+            return;
+        }
+        let watchers = self
+            .watchers
+            .read()
+            .unwrap_or_else(|poison| poison.into_inner());
+        for watcher in &watchers.watchers {
+            func(watcher);
+        }
+    }
+
+    /// Check `WatchCondition::LiveBytes` watchers after live memory changed. Must be called with `IN_ALLOC` set.
+    #[cfg_attr(not(feature = "backtrace"), allow(irrefutable_let_patterns))]
+    pub(crate) fn check_live_watchers(&'static self) {
+        self.for_each_watcher(|watcher| {
+            let WatchCondition::LiveBytes(threshold) = watcher.condition else {
+                return;
+            };
+            let bytes = self.live.load().bytes as u64;
+            if bytes > threshold {
+                if watcher.armed.swap(false, Ordering::Relaxed) {
+                    queue_event(
+                        watcher,
+                        WatchEvent {
+                            condition: watcher.condition,
+                            bytes,
+                            #[cfg(feature = "backtrace")]
+                            trace_hash: None,
+                            tracker: self,
+                        },
+                    );
+                }
+            } else if bytes < watcher.rearm_below {
+                watcher.armed.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Check `WatchCondition::BacktraceInUse` watchers after the in-use bytes of a backtrace changed.
+    /// Must be called with `IN_ALLOC` set.
+    #[cfg(feature = "backtrace")]
+    pub(crate) fn check_trace_watchers(&'static self, trace_hash: u64, in_use: u64) {
+        self.for_each_watcher(|watcher| {
+            let WatchCondition::BacktraceInUse(threshold) = watcher.condition else {
+                return;
+            };
+            if in_use > threshold {
+                if watcher.lock_fired().insert(trace_hash) {
+                    watcher.fired_len.fetch_add(1, Ordering::Relaxed);
+                    queue_event(
+                        watcher,
+                        WatchEvent {
+                            condition: watcher.condition,
+                            bytes: in_use,
+                            trace_hash: Some(trace_hash),
+                            tracker: self,
+                        },
+                    );
+                }
+            } else if in_use < watcher.rearm_below
+                && watcher.fired_len.load(Ordering::Relaxed) != 0
+                && watcher.lock_fired().remove(&trace_hash)
+            {
+                watcher.fired_len.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        sync::atomic::AtomicU64,
+    };
+
+    use super::*;
+    use crate::{AllocTrack, BacktraceMode};
+
+    #[test]
+    fn test_live_bytes_watcher() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker);
+        // (event bytes, live bytes seen from the callback) of every delivery
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let fired_in_callback = fired.clone();
+        tracker.watch_with_hysteresis(WatchCondition::LiveBytes(1000), 500, move |event| {
+            // runs outside of the allocator, so reports are available
+            let live_bytes = event.tracker().global_stats().live_bytes;
+            fired_in_callback
+                .lock()
+                .unwrap()
+                .push((event.bytes, live_bytes));
+        });
+        let fired_count = || fired.lock().unwrap().len();
+        let layout = Layout::from_size_align(600, 8).unwrap();
+        let small = Layout::from_size_align(300, 8).unwrap();
+        unsafe {
+            let first = alloc.alloc(layout);
+            assert_eq!(fired_count(), 0);
+            let second = alloc.alloc(layout);
+            assert_eq!(fired_count(), 1);
+            // still above the threshold, and then not dropping far enough to rearm
+            let third = alloc.alloc(small);
+            alloc.dealloc(third, small);
+            alloc.dealloc(second, layout);
+            let second = alloc.alloc(layout);
+            assert_eq!(fired_count(), 1);
+            // dropping below 500 bytes rearms
+            alloc.dealloc(second, layout);
+            alloc.dealloc(first, layout);
+            let first = alloc.alloc(layout);
+            let second = alloc.alloc(layout);
+            assert_eq!(fired_count(), 2);
+            alloc.dealloc(first, layout);
+            alloc.dealloc(second, layout);
+        }
+        assert_eq!(*fired.lock().unwrap(), [(1200, 1200), (1200, 1200)]);
+    }
+
+    #[test]
+    fn test_panicking_watcher() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::None).with_tracker(tracker);
+        let fired = Arc::new(AtomicU64::new(0));
+        let fired_in_callback = fired.clone();
+        tracker.watch_with_hysteresis(WatchCondition::LiveBytes(100), 0, move |_| {
+            fired_in_callback.fetch_add(1, Ordering::Relaxed);
+            panic!("watcher panicked");
+        });
+        let layout = Layout::from_size_align(200, 8).unwrap();
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            alloc.dealloc(ptr, layout);
+            // the panic neither escaped the allocator nor stopped later deliveries
+            let ptr = alloc.alloc(layout);
+            alloc.dealloc(ptr, layout);
+        }
+        assert_eq!(fired.load(Ordering::Relaxed), 2);
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn test_backtrace_watcher() {
+        let tracker: &'static Tracker = Box::leak(Box::new(Tracker::new()));
+        let alloc = AllocTrack::new(System, BacktraceMode::Short).with_tracker(tracker);
+        // (trace hash, whether it resolved, in-use bytes of its backtrace in the report) of every delivery
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let fired_in_callback = fired.clone();
+        tracker.watch(WatchCondition::BacktraceInUse(1000), move |event| {
+            let trace_hash = event.trace_hash.unwrap();
+            let resolved = event.backtrace().is_some();
+            let report = event.tracker().backtrace_report(|_, _| true);
+            let in_use = report
+                .0
+                .iter()
+                .find(|(trace, _)| trace.hash() == trace_hash)
+                .map(|(_, metric)| metric.in_use());
+            fired_in_callback
+                .lock()
+                .unwrap()
+                .push((trace_hash, resolved, in_use));
+        });
+        let layout = Layout::from_size_align(600, 8).unwrap();
+        unsafe {
+            let mut ptrs = Vec::new();
+            for _ in 0..4 {
+                ptrs.push(alloc.alloc(layout));
+            }
+            for ptr in ptrs {
+                alloc.dealloc(ptr, layout);
+            }
+        }
+        let fired = fired.lock().unwrap();
+        assert_eq!(fired.len(), 1);
+        let (_, resolved, in_use) = fired[0];
+        assert!(resolved);
+        assert_eq!(in_use, Some(1200));
+    }
+}